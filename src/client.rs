@@ -1,30 +1,67 @@
 use std::borrow::Borrow;
+use std::any::Any;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Arc, Mutex, Weak};
-use std::sync::mpsc::{IntoIter, sync_channel};
-use ::{EngineError, EngineEvent, HANDLER_LOCK_POISONED, Packet};
-use connection::{Connection, State};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use ::{BufferLimits, CookieJar, EngineError, EngineEvent, HANDLER_LOCK_POISONED, Packet, Protocol, ProxyConfig, ReconnectPolicy, TransportType};
+use connection::{Connection, State, WeakConnection};
 use eventual::{Async, Future};
+use hyper::header::Headers;
+use transports::{Config, Options, RESERVED_PARAMETERS};
+#[cfg(feature = "stream")]
+use ::{EventStream, PacketSink};
+#[cfg(feature = "ssl")]
+use ::TlsConfig;
+#[cfg(feature = "stream")]
+use futures::sync::mpsc::unbounded;
 use url::Url;
 use uuid::Uuid;
 
 type Callbacks = Arc<Mutex<CallbacksDictionary>>;
-type CallbacksDictionary = HashMap<Uuid, Box<FnMut(&EngineEvent) + 'static + Send>>;
+type CallbacksDictionary = HashMap<Uuid, Arc<Mutex<Handler>>>;
+type Handler = Box<FnMut(&EngineEvent) -> bool + 'static + Send>;
+type Consumer = Arc<Mutex<Option<Box<FnMut(EngineEvent) + 'static + Send>>>>;
+
+const GUARD_RELEASED: &'static str = "The registration has already been released.";
+
+/// The number of events a receiver buffers until they are received.
+pub const RECEIVER_CAPACITY: usize = 256;
 
 /// An instance of an engine.io connection.
+///
+/// Clones of a client share the connection, which is closed once the
+/// last clone has been dropped. Handlers capturing a clone keep the
+/// connection open, until it is disconnected explicitly.
 #[derive(Clone)]
 pub struct Client {
     connection: Connection,
-    handlers: Callbacks
+    consumer: Consumer,
+    guard: Arc<DropGuard>,
+    handlers: Callbacks,
+    path: Option<String>
 }
 
 impl Client {
     /// Initializes a new client.
     pub fn new() -> Client {
+        Client::with_protocol(Protocol::default())
+    }
+
+    /// Initializes a new client speaking the given protocol version.
+    ///
+    /// Use `Protocol::V4` to talk to engine.io 4+ (socket.io 3+) servers.
+    pub fn with_protocol(protocol: Protocol) -> Client {
+        let connection = Connection::with_protocol(protocol);
         Client {
-            connection: Connection::new(),
-            handlers: Arc::new(Mutex::new(HashMap::new()))
+            guard: Arc::new(DropGuard(connection.clone())),
+            connection: connection,
+            consumer: Arc::new(Mutex::new(None)),
+            handlers: Arc::new(Mutex::new(HashMap::new())),
+            path: None
         }
     }
 
@@ -43,14 +80,16 @@ impl Client {
     /// computation in the background.
     pub fn connect<U: Borrow<Url>>(&self, url: &U) -> Future<bool, EngineError> {
         if self.state() != State::Connected {
-            let handlers = self.handlers.clone();
+            let dispatcher = spawn_dispatcher(self.handlers.clone(), self.consumer.clone(), self.connection.downgrade());
             let callback_b = Box::new(move |ev: EngineEvent| {
-                for func in handlers.lock().expect(HANDLER_LOCK_POISONED).values_mut() {
-                    func(&ev);
-                }
+                let _ = dispatcher.send(ev);
             });
-            self.connection.connect_with_default_if_none(url.borrow().clone(), callback_b)
-                           .map(|_| true)
+            let url = url.borrow().clone();
+            let connect_f = match self.path {
+                Some(ref path) => self.connection.connect_with_path(url, path, callback_b),
+                None => self.connection.connect_with_default_if_none(url, callback_b)
+            };
+            connect_f.map(|_| true)
         } else {
             Future::of(false)
         }
@@ -61,8 +100,44 @@ impl Client {
         &self.connection
     }
 
-    /// Disconnects the client from the endpoint.
-    pub fn disconnect(&self) -> Future<bool, EngineError> {
+    /// Gets the size of the payloads of the packets that have been sent
+    /// but haven't been flushed yet in bytes.
+    ///
+    /// Producers can use this to throttle themselves before the buffer
+    /// limits are reached.
+    pub fn buffered_amount(&self) -> usize {
+        self.connection.buffered_amount()
+    }
+
+    /// Gets the jar of the cookies sent with every request.
+    ///
+    /// The jar is filled from the responses of the server, e.g. with
+    /// the cookies load balancers use to pin sessions to a node. It can
+    /// be inspected, and seeded before connecting.
+    pub fn cookies(&self) -> CookieJar {
+        self.connection.cookies()
+    }
+
+    /// Disconnects the client from the endpoint once the packets sent
+    /// have been flushed.
+    ///
+    /// Waits at most for the given timeout for the outgoing packets to
+    /// drain, then sends the close packet and waits for the transport to
+    /// shut down. Packets that couldn't be sent in time fail with
+    /// `EngineError::Dropped`.
+    pub fn close_gracefully(&self, timeout: Duration) -> Future<bool, EngineError> {
+        if self.state() == State::Connected {
+            self.connection.close_gracefully(timeout)
+        } else {
+            Future::of(false)
+        }
+    }
+
+    /// Disconnects the client from the endpoint right away.
+    ///
+    /// Packets that haven't been sent yet are dropped, their sends fail
+    /// with `EngineError::Dropped`.
+    pub fn close_now(&self) -> Future<bool, EngineError> {
         if self.state() == State::Connected {
             self.connection.disconnect().map(|_| true)
         } else {
@@ -70,11 +145,134 @@ impl Client {
         }
     }
 
+    /// Disconnects the client from the endpoint right away, see
+    /// `close_now`.
+    pub fn disconnect(&self) -> Future<bool, EngineError> {
+        self.close_now()
+    }
+
+    /// Starts receiving the events of the connection through the
+    /// returned receiver.
+    ///
+    /// The events are received after the registered handlers have been
+    /// called. Up to `RECEIVER_CAPACITY` events are buffered until they
+    /// are received; the events arriving while the buffer is full are
+    /// dropped, so that a receiver that isn't drained neither blocks the
+    /// handlers nor grows without bounds. The receiver still ends once
+    /// the connection has been closed. It replaces the receiver or
+    /// stream the events have been delivered to before, which ends.
+    ///
+    /// The events are received through a receiver rather than the
+    /// client itself, so that they are only buffered for the clients
+    /// receiving them, and clones of a client don't compete for them.
+    pub fn receiver(&self) -> EventReceiver {
+        let (tx, rx) = sync_channel(RECEIVER_CAPACITY);
+        *self.consumer.lock().expect(HANDLER_LOCK_POISONED) = Some(Box::new(move |ev| {
+            let _ = tx.try_send(ev);
+        }));
+        EventReceiver(rx)
+    }
+
     /// Registers a callback for event receival.
-    pub fn register<H: FnMut(&EngineEvent) + 'static + Send>(&self, handler: H) -> Registration {
-        let uuid = Uuid::new_v4();
-        self.handlers.lock().expect(HANDLER_LOCK_POISONED).insert(uuid.clone(), Box::new(handler));
-        Registration(Arc::downgrade(&self.handlers), uuid)
+    ///
+    /// The callbacks are called one event after the other on a dedicated
+    /// thread, and may use the client themselves, e.g. to register other
+    /// callbacks. A callback that panics is unregistered, and the panic
+    /// is reported through a `HandlerPanic` event.
+    pub fn register<H: FnMut(&EngineEvent) + 'static + Send>(&self, mut handler: H) -> Registration {
+        self.add_handler(Box::new(move |ev| {
+            handler(ev);
+            true
+        }))
+    }
+
+    /// Registers a callback for event receival, which is unregistered
+    /// once the returned guard is dropped.
+    pub fn register_scoped<H: FnMut(&EngineEvent) + 'static + Send>(&self, handler: H) -> RegistrationGuard {
+        self.register(handler).guard()
+    }
+
+    /// Gets the number of registered callbacks.
+    pub fn registration_count(&self) -> usize {
+        self.handlers.lock().expect(HANDLER_LOCK_POISONED).len()
+    }
+
+    /// Gets the registrations of all registered callbacks.
+    ///
+    /// Mainly useful to find callbacks that have been forgotten to be
+    /// unregistered.
+    pub fn registrations(&self) -> Vec<Registration> {
+        self.handlers.lock().expect(HANDLER_LOCK_POISONED).keys()
+            .map(|uuid| Registration(Arc::downgrade(&self.handlers), uuid.clone()))
+            .collect()
+    }
+
+    /// Registers a callback for the events matching the given predicate.
+    pub fn register_filtered<P, H>(&self, mut predicate: P, mut handler: H) -> Registration
+        where P: FnMut(&EngineEvent) -> bool + 'static + Send,
+              H: FnMut(&EngineEvent) + 'static + Send {
+        self.add_handler(Box::new(move |ev| {
+            if predicate(ev) {
+                handler(ev);
+            }
+            true
+        }))
+    }
+
+    /// Registers a callback for the first event matching the given
+    /// predicate. The callback is unregistered after it has been called.
+    pub fn register_once<P, H>(&self, mut predicate: P, handler: H) -> Registration
+        where P: FnMut(&EngineEvent) -> bool + 'static + Send,
+              H: FnOnce(&EngineEvent) + 'static + Send {
+        let mut handler = Some(handler);
+        self.add_handler(Box::new(move |ev| {
+            if !predicate(ev) {
+                return true;
+            }
+            if let Some(handler) = handler.take() {
+                handler(ev);
+            }
+            false
+        }))
+    }
+
+    /// Registers a callback for established connections, which receives
+    /// the connection config.
+    pub fn on_connect<H: FnMut(&Config) + 'static + Send>(&self, mut handler: H) -> Registration {
+        self.register(move |ev| {
+            if let EngineEvent::Connect(ref cfg) = *ev {
+                handler(cfg);
+            }
+        })
+    }
+
+    /// Registers a callback for disconnections.
+    pub fn on_disconnect<H: FnMut() + 'static + Send>(&self, mut handler: H) -> Registration {
+        self.register(move |ev| {
+            if let EngineEvent::Disconnect = *ev {
+                handler();
+            }
+        })
+    }
+
+    /// Registers a callback for errors, i.e. for connections failing to
+    /// be established (`ConnectError`) or dying (`Error`).
+    pub fn on_error<H: FnMut(&EngineError) + 'static + Send>(&self, mut handler: H) -> Registration {
+        self.register(move |ev| {
+            match *ev {
+                EngineEvent::ConnectError(ref err) | EngineEvent::Error(ref err) => handler(err),
+                _ => {}
+            }
+        })
+    }
+
+    /// Registers a callback for incoming messages.
+    pub fn on_message<H: FnMut(&Packet) + 'static + Send>(&self, mut handler: H) -> Registration {
+        self.register(move |ev| {
+            if let EngineEvent::Message(ref packet) = *ev {
+                handler(packet);
+            }
+        })
     }
 
     /// Sends a packet to the other endpoint.
@@ -95,32 +293,522 @@ impl Client {
         self.connection.send_all(packets)
     }
 
+    /// Sets additional HTTP headers sent with every request, including
+    /// the websocket handshake, e.g. `Authorization`.
+    ///
+    /// Takes effect on the next connect, which includes automatic
+    /// reconnects.
+    pub fn set_headers(&self, headers: Headers) {
+        self.connection.set_headers(headers)
+    }
+
+    /// Sets how the server is reached, i.e. whether and which HTTP proxy
+    /// is used.
+    ///
+    /// By default, the proxies from the `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `NO_PROXY` environment variables are used.
+    pub fn set_proxy(&self, proxy: ProxyConfig) {
+        self.connection.set_proxy(proxy)
+    }
+
+    /// Sets the policy used to reconnect automatically after the
+    /// connection has been lost due to an error.
+    ///
+    /// Pass `None` (the default) to disable automatic reconnection.
+    /// Reconnection attempts are reported through the `ReconnectAttempt`,
+    /// `Reconnect` and `ReconnectFailed` events.
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) {
+        self.connection.set_reconnect_policy(policy)
+    }
+
+    /// Sets the configuration of TLS connections, i.e. additional root
+    /// certificates, a client certificate or the SNI host name.
+    ///
+    /// Takes effect on the next connect, which includes automatic
+    /// reconnects.
+    #[cfg(feature = "ssl")]
+    pub fn set_tls(&self, tls: TlsConfig) {
+        self.connection.set_tls(tls)
+    }
+
+    /// Splits the client into a sink sending packets and a stream of
+    /// the events of the connection.
+    ///
+    /// The events are still dispatched to the registered handlers before
+    /// they are put into the stream. The connection is closed once the
+    /// stream is dropped.
+    #[cfg(feature = "stream")]
+    pub fn split(self) -> (PacketSink, EventStream) {
+        let sink = PacketSink::new(self.connection.clone());
+        (sink, self.into_stream())
+    }
+
+    /// Turns the client into a stream of the events of the connection.
+    ///
+    /// The events are still dispatched to the registered handlers before
+    /// they are put into the stream. The connection is closed once the
+    /// stream is dropped.
+    ///
+    /// The stream replaces the receiver the events have been delivered
+    /// to before, which ends.
+    #[cfg(feature = "stream")]
+    pub fn into_stream(self) -> EventStream {
+        let (tx, rx) = unbounded();
+        *self.consumer.lock().expect(HANDLER_LOCK_POISONED) = Some(Box::new(move |ev| {
+            let _ = tx.send(ev);
+        }));
+        EventStream::new(self, rx)
+    }
+
     /// Gets the connection state.
     pub fn state(&self) -> State {
         self.connection.state()
     }
 }
 
+impl Client {
+    fn add_handler(&self, handler: Handler) -> Registration {
+        let uuid = Uuid::new_v4();
+        self.handlers.lock().expect(HANDLER_LOCK_POISONED).insert(uuid.clone(), Arc::new(Mutex::new(handler)));
+        Registration(Arc::downgrade(&self.handlers), uuid)
+    }
+}
+
 impl Debug for Client {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         write!(formatter, "Client {{ connection: {:?}, ... }}", self.connection)
     }
 }
 
-impl Drop for Client {
+/// Disconnects the connection of a client once the last clone of the
+/// client has been dropped.
+#[derive(Debug)]
+struct DropGuard(Connection);
+
+impl Drop for DropGuard {
     fn drop(&mut self) {
-        let _ = self.disconnect().await();
+        if self.0.state() == State::Connected {
+            let _ = self.0.disconnect().await();
+        }
+    }
+}
+
+/// Starts the thread calling the handlers with the events of a
+/// connection sent through the returned channel.
+///
+/// Every connect gets a thread of its own, which runs until the
+/// connection has been closed for good (see `is_final`) or all senders
+/// have been dropped. This keeps clients captured by their own handlers
+/// from keeping the thread alive.
+fn spawn_dispatcher(handlers: Callbacks, consumer: Consumer, connection: WeakConnection) -> Sender<EngineEvent> {
+    let (tx, rx) = channel::<EngineEvent>();
+    thread::Builder::new().name("Engine.io dispatcher thread".to_owned()).spawn(move || {
+        for ev in rx {
+            let is_last = is_final(&ev, &connection);
+            let mut queue = vec![ev];
+            while !queue.is_empty() {
+                let ev = queue.remove(0);
+                queue.extend(dispatch(&handlers, &ev).into_iter().map(EngineEvent::HandlerPanic));
+
+                // The consumer takes ownership of the event after the
+                // handlers have seen it.
+                if let Some(ref mut consume) = *consumer.lock().expect(HANDLER_LOCK_POISONED) {
+                    consume(ev);
+                }
+            }
+
+            // No more events follow, so the consumer is dropped to end
+            // its receiver.
+            if is_last {
+                *consumer.lock().expect(HANDLER_LOCK_POISONED) = None;
+                return;
+            }
+        }
+    }).expect("Failed to spawn event dispatcher thread.");
+    tx
+}
+
+/// Checks whether no more events follow the given one until the
+/// connection is connected again, i.e. whether the connection has been
+/// closed or lost without being reconnected automatically.
+fn is_final(ev: &EngineEvent, connection: &WeakConnection) -> bool {
+    match *ev {
+        EngineEvent::Disconnect | EngineEvent::ReconnectFailed => true,
+        EngineEvent::Error(_) => connection.upgrade().map_or(true, |conn| conn.reconnect_policy().is_none()),
+        _ => false
+    }
+}
+
+/// Calls the handlers with the given event and removes the ones that
+/// are done or have panicked.
+///
+/// The handlers are called without holding the lock of the dictionary,
+/// so that they can register and unregister handlers themselves.
+///
+/// ## Returns
+/// The messages of the panics of the handlers.
+fn dispatch(handlers: &Callbacks, ev: &EngineEvent) -> Vec<String> {
+    let snapshot: Vec<(Uuid, Arc<Mutex<Handler>>)> = handlers.lock().expect(HANDLER_LOCK_POISONED).iter()
+        .map(|(uuid, func)| (uuid.clone(), func.clone()))
+        .collect();
+
+    let mut panics = Vec::new();
+    for (uuid, func) in snapshot {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut func = func.lock().expect(HANDLER_LOCK_POISONED);
+            func(ev)
+        }));
+        let is_done = match res {
+            Ok(keep) => !keep,
+            Err(payload) => {
+                panics.push(panic_message(payload));
+                true
+            }
+        };
+        if is_done {
+            handlers.lock().expect(HANDLER_LOCK_POISONED).remove(&uuid);
+        }
+    }
+    panics
+}
+
+/// Extracts the message out of the payload of a panic.
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast_ref::<&'static str>() {
+            Some(msg) => (*msg).to_owned(),
+            None => "Unknown panic.".to_owned()
+        }
+    }
+}
+
+/// Receives the events of a client, see `Client::receiver`.
+///
+/// The receiver ends once the connection has been closed or lost for
+/// good, i.e. after a `Disconnect`, a `ReconnectFailed` or an `Error`
+/// that isn't followed by an automatic reconnection.
+#[derive(Debug)]
+pub struct EventReceiver(Receiver<EngineEvent>);
+
+impl EventReceiver {
+    /// Gets an iterator blocking for the events of the connection,
+    /// which ends with the receiver.
+    pub fn iter(&self) -> Incoming {
+        Incoming(self)
+    }
+
+    /// Blocks until the next event of the connection is received.
+    ///
+    /// The method must not be called from within a handler, since the
+    /// handler would wait for itself.
+    ///
+    /// ## Returns
+    /// An error, if the receiver has ended and all of its events have
+    /// been received.
+    pub fn recv(&self) -> Result<EngineEvent, RecvError> {
+        self.0.recv()
+    }
+
+    /// Waits at most the given duration for the next event of the
+    /// connection.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<EngineEvent, RecvTimeoutError> {
+        self.0.recv_timeout(timeout)
+    }
+
+    /// Gets the next event of the connection, if one has already been
+    /// received.
+    pub fn try_recv(&self) -> Result<EngineEvent, TryRecvError> {
+        self.0.try_recv()
+    }
+}
+
+impl<'a> IntoIterator for &'a EventReceiver {
+    type Item = EngineEvent;
+    type IntoIter = Incoming<'a>;
+
+    fn into_iter(self) -> Incoming<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the events of a receiver, blocking until the next
+/// event is received.
+#[derive(Debug)]
+pub struct Incoming<'a>(&'a EventReceiver);
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = EngineEvent;
+
+    fn next(&mut self) -> Option<EngineEvent> {
+        self.0.recv().ok()
+    }
+}
+
+/// Builds a `Client` with a custom configuration.
+///
+/// ## Example
+/// ```
+/// # use engineio::{ClientBuilder, TransportType};
+/// # use std::time::Duration;
+/// let client = ClientBuilder::new()
+///     .path("/socket.io/")
+///     .transports(&[TransportType::WebSocket, TransportType::Polling])
+///     .request_timeout(Duration::from_secs(10))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    // Kept apart from the options, since clones of a jar share their
+    // cookies, but clones of the builder must not.
+    cookies: Vec<(String, String)>,
+    options: Options,
+    path: Option<String>,
+    transports: Vec<TransportType>
+}
+
+impl ClientBuilder {
+    /// Creates a new builder with the default configuration.
+    pub fn new() -> ClientBuilder {
+        ClientBuilder {
+            cookies: Vec::new(),
+            options: Options::default(),
+            path: None,
+            transports: vec![TransportType::Polling, TransportType::WebSocket]
+        }
+    }
+
+    /// Validates the configuration and builds the client.
+    pub fn build(self) -> Result<Client, EngineError> {
+        try!(self.validate());
+
+        let mut client = Client::with_protocol(self.options.protocol);
+        client.path = self.path;
+        {
+            let conn = &client.connection;
+            apply_tls(conn, &self.options);
+            conn.set_buffer_limits(self.options.buffer_limits);
+            conn.set_handshake_timeout(self.options.handshake_timeout);
+            conn.set_headers(self.options.headers);
+            conn.set_linger(self.options.linger);
+            conn.set_max_payload_size(self.options.max_payload_size);
+            conn.set_proxy(self.options.proxy);
+            conn.set_query(self.options.query);
+            conn.set_request_timeout(self.options.request_timeout);
+            conn.set_timestamp_param(self.options.timestamp_param);
+            try!(conn.set_transports(self.transports));
+            conn.set_worker_threads(self.options.worker_threads);
+
+            let cookies = conn.cookies();
+            for (name, value) in self.cookies {
+                cookies.set(name, value);
+            }
+        }
+        Ok(client)
+    }
+
+    /// Limits the packets buffered for sending and sets what happens
+    /// when the limits are exceeded.
+    ///
+    /// Default: unlimited.
+    pub fn buffer_limits(mut self, limits: BufferLimits) -> ClientBuilder {
+        self.options.buffer_limits = limits;
+        self
+    }
+
+    /// Adds a cookie that is sent with every request.
+    pub fn cookie<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> ClientBuilder {
+        self.cookies.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets how long the handshake may take before it is given up.
+    ///
+    /// Default: 5 seconds.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.options.handshake_timeout = timeout;
+        self
+    }
+
+    /// Adds an HTTP header that is sent with every request, including
+    /// the websocket handshake.
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> ClientBuilder {
+        self.options.headers.set_raw(name.into(), vec![value.into().into_bytes()]);
+        self
+    }
+
+    /// Sets how long the polling transport gathers packets before
+    /// sending them in a single request.
+    ///
+    /// Default: 0.
+    pub fn linger(mut self, linger: Duration) -> ClientBuilder {
+        self.options.linger = linger;
+        self
+    }
+
+    /// Sets the maximum size of the packet payloads batched into a
+    /// single polling request in bytes.
+    ///
+    /// Default: unlimited.
+    pub fn max_payload_size(mut self, size: usize) -> ClientBuilder {
+        self.options.max_payload_size = Some(size);
+        self
+    }
+
+    /// Sets the path of the engine.io endpoint, overriding the one in
+    /// the URL passed to `Client::connect`.
+    ///
+    /// Default: the path of the URL, or `/engine.io/` if it has none.
+    pub fn path<S: Into<String>>(mut self, path: S) -> ClientBuilder {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sets the protocol version to speak.
+    ///
+    /// Default: `Protocol::V3`.
+    pub fn protocol(mut self, protocol: Protocol) -> ClientBuilder {
+        self.options.protocol = protocol;
+        self
+    }
+
+    /// Sets how the server is reached, i.e. whether and which HTTP proxy
+    /// is used.
+    ///
+    /// Default: `ProxyConfig::Environment`.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> ClientBuilder {
+        self.options.proxy = proxy;
+        self
+    }
+
+    /// Adds a query parameter that is sent with every request.
+    pub fn query<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> ClientBuilder {
+        self.options.query.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sets the read and write timeout of the HTTP requests.
+    ///
+    /// Default: 5 seconds.
+    pub fn request_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.options.request_timeout = timeout;
+        self
+    }
+
+    /// Sets the name of the cache-busting timestamp query parameter.
+    ///
+    /// Default: `t`.
+    pub fn timestamp_param<S: Into<String>>(mut self, name: S) -> ClientBuilder {
+        self.options.timestamp_param = name.into();
+        self
+    }
+
+    /// Sets the configuration of TLS connections.
+    ///
+    /// Default: the system's root certificates, no client certificate.
+    #[cfg(feature = "ssl")]
+    pub fn tls(mut self, tls: TlsConfig) -> ClientBuilder {
+        self.options.tls = tls;
+        self
+    }
+
+    /// Sets the transports the client may use in the order of preference.
+    ///
+    /// Default: polling, then upgrading to websocket.
+    pub fn transports(mut self, transports: &[TransportType]) -> ClientBuilder {
+        self.transports = transports.to_vec();
+        self
+    }
+
+    /// Sets the number of threads doing the HTTP requests of the polling
+    /// transport.
+    ///
+    /// One thread does the polls and the other one sends the packets, so
+    /// that sends never wait for a long poll to return. With a single
+    /// thread, both share it; more than two threads aren't used, since
+    /// there is only ever one poll and one POST request in flight.
+    ///
+    /// Default: 2.
+    pub fn worker_threads(mut self, count: usize) -> ClientBuilder {
+        self.options.worker_threads = count;
+        self
+    }
+
+    fn validate(&self) -> Result<(), EngineError> {
+        let opts = &self.options;
+        if self.transports.is_empty() {
+            return Err(EngineError::invalid_config("At least one transport must be allowed."));
+        }
+        if self.transports.iter().enumerate().any(|(i, t)| self.transports[..i].contains(t)) {
+            return Err(EngineError::invalid_config("Every transport may only be listed once."));
+        }
+        if self.path.as_ref().map_or(false, |p| !p.starts_with('/')) {
+            return Err(EngineError::invalid_config("The path must start with a slash."));
+        }
+        if opts.timestamp_param.is_empty() || RESERVED_PARAMETERS.contains(&&opts.timestamp_param[..]) {
+            return Err(EngineError::invalid_config("The timestamp parameter name must neither be empty nor reserved by engine.io."));
+        }
+        if opts.query.iter().any(|&(ref key, _)| RESERVED_PARAMETERS.contains(&&key[..]) || *key == opts.timestamp_param) {
+            return Err(EngineError::invalid_config("Query parameters must not override the ones set by engine.io."));
+        }
+        if opts.handshake_timeout == Duration::from_millis(0) || opts.request_timeout == Duration::from_millis(0) {
+            return Err(EngineError::invalid_config("Timeouts must be greater than zero."));
+        }
+        if opts.buffer_limits.max_packets == Some(0) || opts.buffer_limits.max_bytes == Some(0) {
+            return Err(EngineError::invalid_config("Buffer limits must be greater than zero."));
+        }
+        if opts.max_payload_size == Some(0) {
+            return Err(EngineError::invalid_config("The maximum payload size must be greater than zero."));
+        }
+        if opts.worker_threads == 0 {
+            return Err(EngineError::invalid_config("At least one worker thread is required."));
+        }
+        Ok(())
+    }
+}
+
+/// Applies the TLS configuration of the given options to a connection.
+#[cfg(not(feature = "ssl"))]
+fn apply_tls(_: &Connection, _: &Options) {}
+
+/// Applies the TLS configuration of the given options to a connection.
+#[cfg(feature = "ssl")]
+fn apply_tls(conn: &Connection, opts: &Options) {
+    conn.set_tls(opts.tls.clone());
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder::new()
     }
 }
 
 /// Represents a callback registration. Use this to unregister
 /// a previously registered engine.io callback.
 ///
-/// The callback is _not_ unregistered when this struct is dropped.
+/// The callback is _not_ unregistered when this struct is dropped,
+/// use a `RegistrationGuard` for that.
 #[derive(Clone)]
 pub struct Registration(Weak<Mutex<CallbacksDictionary>>, Uuid);
 
 impl Registration {
+    /// Turns the registration into a guard unregistering the callback
+    /// when it is dropped.
+    pub fn guard(self) -> RegistrationGuard {
+        RegistrationGuard(Some(self))
+    }
+
+    /// Gets the unique ID of the registration.
+    pub fn id(&self) -> Uuid {
+        self.1.clone()
+    }
+
+    /// Checks whether the callback is still registered.
+    pub fn is_registered(&self) -> bool {
+        self.0.upgrade().map_or(false, |dict| dict.lock().expect(HANDLER_LOCK_POISONED).contains_key(&self.1))
+    }
+
     /// Unregisters the callback from the engine.io client.
     pub fn unregister(self) {
         if let Some(dict) = self.0.upgrade() {
@@ -133,4 +821,293 @@ impl Debug for Registration {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         write!(formatter, "Registration(..., {:?})", self.1)
     }
-}
\ No newline at end of file
+}
+
+/// Unregisters a callback when it is dropped.
+#[derive(Debug)]
+pub struct RegistrationGuard(Option<Registration>);
+
+impl RegistrationGuard {
+    /// Gets the guarded registration.
+    pub fn registration(&self) -> &Registration {
+        self.0.as_ref().expect(GUARD_RELEASED)
+    }
+
+    /// Releases the registration from the guard, so that the callback
+    /// stays registered.
+    pub fn release(mut self) -> Registration {
+        self.0.take().expect(GUARD_RELEASED)
+    }
+}
+
+impl Drop for RegistrationGuard {
+    fn drop(&mut self) {
+        if let Some(registration) = self.0.take() {
+            registration.unregister();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, ClientBuilder, RECEIVER_CAPACITY, dispatch, spawn_dispatcher};
+    use connection::State;
+    use eventual::Async;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{RecvTimeoutError, Sender, TryRecvError};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use ::{BufferLimits, EngineError, EngineEvent, OpCode, Packet, ReconnectPolicy, TransportType};
+    use testing::serve;
+
+    fn dispatcher(client: &Client) -> Sender<EngineEvent> {
+        spawn_dispatcher(client.handlers.clone(), client.consumer.clone(), client.connection.downgrade())
+    }
+
+    #[test]
+    fn dispatch_typed() {
+        let client = Client::new();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let messages_c = messages.clone();
+        client.on_message(move |packet| messages_c.lock().unwrap().push(packet.clone()));
+
+        dispatch(&client.handlers, &EngineEvent::Disconnect);
+        dispatch(&client.handlers, &EngineEvent::Message(Packet::with_str(OpCode::Message, "Hello")));
+        assert_eq!(*messages.lock().unwrap(), vec![Packet::with_str(OpCode::Message, "Hello")]);
+    }
+
+    #[test]
+    fn dispatch_panic() {
+        let client = Client::new();
+        let calls = Arc::new(Mutex::new(0));
+        let calls_c = calls.clone();
+        client.register(|_| panic!("Handler failed."));
+        client.register(move |_| *calls_c.lock().unwrap() += 1);
+
+        assert_eq!(dispatch(&client.handlers, &EngineEvent::Disconnect), vec!["Handler failed.".to_owned()]);
+        assert_eq!(client.registration_count(), 1);
+        assert!(dispatch(&client.handlers, &EngineEvent::Disconnect).is_empty());
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn dispatch_reentrant() {
+        let client = Client::new();
+        let client_c = client.clone();
+        client.register_once(|_| true, move |_| {
+            client_c.register(|_| {});
+        });
+
+        dispatch(&client.handlers, &EngineEvent::Disconnect);
+        assert_eq!(client.registration_count(), 1);
+    }
+
+    #[test]
+    fn dispatch_once() {
+        let client = Client::new();
+        let calls = Arc::new(Mutex::new(0));
+        let calls_c = calls.clone();
+        client.register_once(|ev| match *ev { EngineEvent::Disconnect => true, _ => false }, move |_| {
+            *calls_c.lock().unwrap() += 1;
+        });
+
+        dispatch(&client.handlers, &EngineEvent::Reconnect(1));
+        assert_eq!(client.handlers.lock().unwrap().len(), 1);
+        dispatch(&client.handlers, &EngineEvent::Disconnect);
+        dispatch(&client.handlers, &EngineEvent::Disconnect);
+        assert_eq!(*calls.lock().unwrap(), 1);
+        assert!(client.handlers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dispatch_stopped() {
+        let client = Client::new();
+        let client_c = client.clone();
+        client.register(move |_| {
+            client_c.registration_count();
+        });
+
+        let tx = dispatcher(&client);
+        tx.send(EngineEvent::Disconnect).unwrap();
+        let start = Instant::now();
+        while tx.send(EngineEvent::Drain).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(1), "The dispatcher outlived the connection.");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn drop_clone() {
+        let client = Client::new();
+        let client_c = client.clone();
+        let is_connected = |ev: &EngineEvent| match *ev { EngineEvent::Connect(_) => true, _ => false };
+        client.register_once(is_connected, move |_| {
+            client_c.registration_count();
+        });
+        client.connect(&serve(Arc::new(Mutex::new(Ok(Vec::new()))), true)).await().unwrap();
+
+        // The handler and its clone of the client are dropped once it
+        // has been called, which must not close the connection.
+        let start = Instant::now();
+        while client.registration_count() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "The handler wasn't called.");
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(client.state(), State::Connected);
+    }
+
+    #[test]
+    fn registration_guard() {
+        let client = Client::new();
+        let registration = client.register(|_| {});
+        {
+            let _guard = client.register_scoped(|_| {});
+            assert_eq!(client.registration_count(), 2);
+        }
+        assert_eq!(client.registration_count(), 1);
+        assert!(registration.is_registered());
+
+        let registration = client.register_scoped(|_| {}).release();
+        assert_eq!(client.registrations().len(), 2);
+        registration.unregister();
+        assert_eq!(client.registration_count(), 1);
+    }
+
+    #[test]
+    fn recv_empty() {
+        let client = Client::new();
+        let events = client.receiver();
+        assert_eq!(events.try_recv().err(), Some(TryRecvError::Empty));
+        assert_eq!(events.recv_timeout(Duration::from_millis(10)).err(), Some(RecvTimeoutError::Timeout));
+    }
+
+    #[test]
+    fn recv_delivered() {
+        let client = Client::new();
+        let handled = Arc::new(Mutex::new(0));
+        let handled_c = handled.clone();
+        client.register(move |_| *handled_c.lock().unwrap() += 1);
+
+        let events = client.receiver();
+        let tx = dispatcher(&client);
+        tx.send(EngineEvent::Message(Packet::with_str(OpCode::Message, "Hello"))).unwrap();
+        tx.send(EngineEvent::Drain).unwrap();
+        tx.send(EngineEvent::Disconnect).unwrap();
+        tx.send(EngineEvent::Drain).unwrap();
+
+        let received: Vec<EngineEvent> = events.iter().collect();
+        assert_eq!(received.len(), 3);
+        match received[0] {
+            EngineEvent::Message(ref packet) => assert_eq!(*packet, Packet::with_str(OpCode::Message, "Hello")),
+            ref ev => panic!("Unexpected event {:?}.", ev)
+        }
+        match received[2] {
+            EngineEvent::Disconnect => {},
+            ref ev => panic!("Unexpected event {:?}.", ev)
+        }
+        assert!(events.recv().is_err());
+        assert!(*handled.lock().unwrap() >= 3);
+    }
+
+    #[test]
+    fn recv_reconnecting() {
+        let client = Client::new();
+        let events = client.receiver();
+        let tx = dispatcher(&client);
+
+        client.set_reconnect_policy(Some(ReconnectPolicy::default()));
+        tx.send(EngineEvent::Error(EngineError::Timeout)).unwrap();
+        tx.send(EngineEvent::ReconnectAttempt(1)).unwrap();
+        assert!(events.recv().is_ok());
+        assert!(events.recv().is_ok());
+        assert_eq!(events.recv_timeout(Duration::from_millis(10)).err(), Some(RecvTimeoutError::Timeout));
+
+        client.set_reconnect_policy(None);
+        tx.send(EngineEvent::Error(EngineError::Timeout)).unwrap();
+        assert!(events.recv().is_ok());
+        assert!(events.recv().is_err());
+    }
+
+    #[test]
+    fn recv_overflow() {
+        let client = Client::new();
+        let events = client.receiver();
+        let tx = dispatcher(&client);
+        for _ in 0..RECEIVER_CAPACITY + 10 {
+            tx.send(EngineEvent::Drain).unwrap();
+        }
+        tx.send(EngineEvent::Disconnect).unwrap();
+
+        // The events exceeding the capacity are dropped, but the receiver
+        // still ends with the connection.
+        let start = Instant::now();
+        while client.consumer.lock().unwrap().is_some() {
+            assert!(start.elapsed() < Duration::from_secs(5), "The dispatcher didn't stop.");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(events.iter().count(), RECEIVER_CAPACITY);
+    }
+
+    #[test]
+    fn recv_replaced() {
+        let client = Client::new();
+        let first = client.receiver();
+        let second = client.receiver();
+        dispatcher(&client).send(EngineEvent::Drain).unwrap();
+
+        assert!(second.recv().is_ok());
+        assert_eq!(first.try_recv().err(), Some(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn build_default() {
+        ClientBuilder::new().build().expect("Failed to build the default client.");
+    }
+
+    #[test]
+    fn build_custom() {
+        let client = ClientBuilder::new()
+            .path("/socket.io/")
+            .query("token", "abc")
+            .header("Authorization", "Bearer abc")
+            .cookie("AWSALB", "node-1")
+            .timestamp_param("ts")
+            .transports(&[TransportType::WebSocket])
+            .handshake_timeout(Duration::from_secs(1))
+            .request_timeout(Duration::from_secs(30))
+            .worker_threads(1)
+            .linger(Duration::from_millis(10))
+            .max_payload_size(64 * 1024)
+            .build()
+            .expect("Failed to build a valid client.");
+        assert_eq!(client.cookies().get("AWSALB"), Some("node-1".to_owned()));
+    }
+
+    #[test]
+    fn build_cloned() {
+        let base = ClientBuilder::new().cookie("AWSALB", "node-1");
+        let derived = base.clone().cookie("session", "abc").build().expect("Failed to build a valid client.");
+        assert_eq!(derived.cookies().get("session"), Some("abc".to_owned()));
+
+        let client = base.build().expect("Failed to build a valid client.");
+        assert_eq!(client.cookies().get("AWSALB"), Some("node-1".to_owned()));
+        assert_eq!(client.cookies().get("session"), None);
+    }
+
+    #[test]
+    fn build_invalid() {
+        assert!(ClientBuilder::new().transports(&[]).build().is_err());
+        assert!(ClientBuilder::new().transports(&[TransportType::Polling, TransportType::Polling]).build().is_err());
+        assert!(ClientBuilder::new().path("engine.io").build().is_err());
+        assert!(ClientBuilder::new().timestamp_param("sid").build().is_err());
+        assert!(ClientBuilder::new().query("EIO", "2").build().is_err());
+        assert!(ClientBuilder::new().query("t", "0").build().is_err());
+        assert!(ClientBuilder::new().request_timeout(Duration::from_millis(0)).build().is_err());
+        assert!(ClientBuilder::new().worker_threads(0).build().is_err());
+        assert!(ClientBuilder::new().max_payload_size(0).build().is_err());
+
+        let limits = BufferLimits { max_packets: Some(0), ..Default::default() };
+        assert!(ClientBuilder::new().buffer_limits(limits).build().is_err());
+    }
+}
@@ -1,14 +1,33 @@
 use super::*;
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::mem;
-use std::ops::{Deref};
-use std::sync::{Arc, Mutex, RwLock};
-use eventual::{Async, Future};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+use eventual::{Async, AsyncError, Complete, Future};
+use heartbeat::{self, Heartbeat, Pulse, Signal};
+use hyper::header::Headers;
+use outgoing::{Amount, Outgoing};
 use transports::*;
 use url::Url;
 
+const CALLBACK_POISONED: &'static str = "Failed to lock connection callback.";
 const CONNECTION_STATE_POISONED: &'static str = "Failed to mutably lock connection state rw-lock.";
 const STATE_POISONED: &'static str = "Failed to lock internal state.";
+const TRANSPORTS_EMPTY: &'static str = "At least one transport must be allowed.";
+const URL_CANNOT_BE_A_BASE: &'static str = "The URL must be able to be a base.";
+const URL_PATH_EMPTY: &'static str = "The URL must contain a path.";
+const URL_SCHEME_INVALID: &'static str = "The URL must have an http, https, ws or wss scheme.";
+
+/// The path of the engine.io endpoint, if there isn't one in the URL.
+const DEFAULT_PATH: &'static str = "/engine.io/";
+
+type Callback = Box<FnMut(EngineEvent) + 'static + Send>;
+pub type SharedCallback = Arc<Mutex<Callback>>;
 
 /// Represents a connection to an engine.io server over a
 /// variety of transports.
@@ -18,8 +37,12 @@ const STATE_POISONED: &'static str = "Failed to lock internal state.";
 /// the messages while a transport is paused and upgraded and
 /// sending the buffered messages when the upgrade is finished.
 ///
-/// Right now this does nothing but forward all messages to
-/// the long polling transport.
+/// Every connection starts out on long polling. If the server
+/// offers the `websocket` upgrade, the connection probes a websocket
+/// in the background and switches over once the probe succeeded.
+/// Where websockets are known to work, the connection can skip
+/// polling altogether (see `set_transports` and
+/// `set_remember_upgrade`).
 #[derive(Clone, Debug)]
 pub struct Connection(Arc<Mutex<ConnectionState>>);
 
@@ -29,37 +52,118 @@ impl Connection {
     /// The path (default: `/engine.io/` for engine.io transports and
     /// `/socket.io/` for socket.io transports) must already be set.
     pub fn new() -> Connection {
+        Connection::with_protocol(Protocol::default())
+    }
+
+    /// Initializes a new connection speaking the given protocol version.
+    pub fn with_protocol(protocol: Protocol) -> Connection {
+        Connection::with_options(Options {
+            protocol: protocol,
+            ..Default::default()
+        })
+    }
+
+    fn with_options(options: Options) -> Connection {
         Connection(Arc::new(Mutex::new(ConnectionState {
+            callback: None,
             cfg: None,
-            transport: None,
+            heartbeat: None,
             connection_state_lock: Arc::new(RwLock::new(State::Pending)),
+            options: options,
+            outgoing: Outgoing::new(),
+            reconnect_policy: None,
+            reconnecting: None,
+            remember_upgrade: false,
+            suspended: None,
+            transport: None,
+            transports: vec![TransportType::Polling, TransportType::WebSocket],
+            upgraded_hosts: HashSet::new(),
             url: None
         })))
     }
 
     /// Closes the current connection, if one is present, and opens up
     /// a new one to the specified URL.
+    ///
+    /// `ws` and `wss` URLs are accepted as well and are treated like
+    /// their `http` and `https` counterparts.
     pub fn connect(&self, url: Url, callback: Box<FnMut(EngineEvent) + 'static + Send>) -> Future<(), EngineError> {
-        assert!(!url.cannot_be_a_base(), "URL must be able to be a base.");
-        assert!(url.scheme() == "http" || url.scheme() == "https", "Url must be an HTTP or HTTPS url.");
-        assert!(!url.path().is_empty(), "Path must be set.");
+        let url = match normalize_url(url) {
+            Ok(url) => url,
+            Err(err) => return Future::error(err)
+        };
+
+        self.cancel_reconnect();
+        self.connect_shared(url, Arc::new(Mutex::new(callback)))
+    }
 
-        let connection_connection_state_lock = {
+    /// Connects to the given URL and reports events to a callback
+    /// that can be reused for automatic reconnection.
+    fn connect_shared(&self, url: Url, user_callback: SharedCallback) -> Future<(), EngineError> {
+        let (connection_state_lock, options, skips_polling, allows_polling, allows_upgrade) = {
             let s = self.0.lock().expect(STATE_POISONED);
-            s.connection_state_lock.clone()
+            (
+                s.connection_state_lock.clone(),
+                s.options.clone(),
+                s.skips_polling(&url),
+                s.transports.contains(&TransportType::Polling),
+                s.transports.contains(&TransportType::WebSocket)
+            )
         };
+        let protocol = options.protocol;
         let state = self.0.clone();
+        let (pulse, signals) = heartbeat::pulse();
+        let recovery = Recovery {
+            callback: user_callback.clone(),
+            state: Arc::downgrade(&self.0),
+            url: url.clone()
+        };
+        let callback = Arc::new(Mutex::new(create_handler(forward_to(&user_callback), connection_state_lock, pulse.clone(), recovery.clone())));
+        let transport_callback = resumable(recovery, true, forward_to(&callback));
 
-        create_connection(url.clone(), callback, connection_connection_state_lock).and_then(move |conn| {
-            let mut state = state.lock().expect(STATE_POISONED);
+        let connect_f = if skips_polling {
+            let socket_f = Socket::connect(url.clone(), options.clone(), forward_to(&callback))
+                .map(|socket| (socket.cfg().clone(), Box::new(socket) as Box<Transport>, true));
+            if !allows_polling {
+                socket_f
+            } else {
+                // Websockets may not work (anymore), e.g. because we're
+                // behind another proxy now.
+                let (url, options) = (url.clone(), options.clone());
+                socket_f.or_else(move |_| {
+                    connect_polling(url, options, transport_callback).map(|(cfg, conn)| (cfg, conn, false))
+                })
+            }
+        } else {
+            connect_polling(url.clone(), options.clone(), transport_callback).map(|(cfg, conn)| (cfg, conn, false))
+        };
+
+        connect_f.and_then(move |(cfg, conn, is_websocket)| {
             {
-                state.cfg = Some(conn.cfg().clone());
+                let mut state = state.lock().expect(STATE_POISONED);
+                if skips_polling && !is_websocket {
+                    state.upgraded_hosts.remove(&host_key(&url));
+                }
+                state.callback = Some(callback.clone());
+                state.cfg = Some(cfg.clone());
+
+                // Set only now, so that a failed connect doesn't keep
+                // the callback alive.
+                state.outgoing.set_callback(user_callback);
+                if let Some(suspended) = state.suspended.take() {
+                    drop_pending(suspended);
+                }
                 *state.connection_state_lock.write().expect(CONNECTION_STATE_POISONED) = State::Connected;
-                state.url = Some(url);
+                state.url = Some(url.clone());
+
+                if let Some(transport) = mem::replace(&mut state.transport, Some(conn)) {
+                    transport.close().fire();
+                }
             }
+            start_heartbeat(&state, &cfg, protocol, pulse, signals, callback.clone());
 
-            if let Some(transport) = mem::replace(&mut state.transport, Some(conn)) {
-                transport.close().fire();
+            if allows_upgrade && !is_websocket && cfg.upgrades().iter().any(|u| u == "websocket") {
+                upgrade(state, url, options, cfg, callback);
             }
 
             Ok(())
@@ -68,13 +172,13 @@ impl Connection {
 
     /// Initializes a new connection to the `/engine.io/`-path of the specified endpoint.
     pub fn connect_with_default(&self, url: Url, callback: Box<FnMut(EngineEvent) + 'static + Send>) -> Future<(), EngineError> {
-        self.connect_with_path(url, "/engine.io/", callback)
+        self.connect_with_path(url, DEFAULT_PATH, callback)
     }
 
     /// Initializes a new connection to the default path if there isn't
-    /// one already inside the URL.
+    /// one already inside the URL, i.e. if its path is just `/`.
     pub fn connect_with_default_if_none(&self, url: Url, callback: Box<FnMut(EngineEvent) + 'static + Send>) -> Future<(), EngineError> {
-        if url.path().is_empty() {
+        if url.path().is_empty() || url.path() == "/" {
             self.connect_with_default(url, callback)
         } else {
             self.connect(url, callback)
@@ -87,12 +191,48 @@ impl Connection {
         self.connect(url, callback)
     }
 
+    /// Gets the jar of the cookies sent with every request.
+    ///
+    /// The jar is shared with the connection, cookies set through it
+    /// are sent with the following requests.
+    pub fn cookies(&self) -> CookieJar {
+        self.0.lock().expect(STATE_POISONED).options.cookies.clone()
+    }
+
+    /// Gets the size of the payloads of the packets that have been sent
+    /// but haven't been flushed yet in bytes.
+    pub fn buffered_amount(&self) -> usize {
+        self.0.lock().expect(STATE_POISONED).outgoing.buffered_amount()
+    }
+
+    /// Checks whether sending the given packets would exceed the limits
+    /// of the buffer of packets waiting to be sent.
+    pub fn exceeds_buffer_limits(&self, packets: &[Packet]) -> bool {
+        let state = self.0.lock().expect(STATE_POISONED);
+        state.outgoing.exceeds(Amount::of(packets), &state.options.buffer_limits)
+    }
+
+    /// Calls the given function once room has been made in the buffer of
+    /// packets waiting to be sent, i.e. once the next pending send has
+    /// been resolved, or right away if there is none.
+    pub fn notify_room<F: FnMut() + Send + 'static>(&self, f: F) {
+        // The function may be called right away, so the lock is released
+        // first.
+        let outgoing = self.0.lock().expect(STATE_POISONED).outgoing.clone();
+        outgoing.notify_room(f)
+    }
+
     /// Gets the connection config.
     pub fn config(&self) -> Option<Config> {
         let internal_state = self.0.lock().expect(STATE_POISONED);
         internal_state.cfg.clone()
     }
 
+    /// Creates a handle of the connection that doesn't keep it alive.
+    pub fn downgrade(&self) -> WeakConnection {
+        WeakConnection(Arc::downgrade(&self.0))
+    }
+
     /// Disconnects the connection.
     ///
     /// ## Returns
@@ -101,10 +241,54 @@ impl Connection {
     /// has been performed because there was no connection to
     /// disconnect in the first place.
     pub fn disconnect(&self) -> Future<bool, EngineError> {
-        if let Some(transport) = self.0.lock().expect(STATE_POISONED).transport.take() {
-            transport.close().map(|_| true)
-        } else {
-            Future::of(false)
+        self.close(None)
+    }
+
+    /// Disconnects the connection once the packets sent through it have
+    /// been flushed.
+    ///
+    /// Waits at most for the given timeout for the pending sends to be
+    /// resolved, then sends the close packet and waits for the transport
+    /// to shut down within what is left of the timeout. Packets that
+    /// couldn't be sent in time fail with `EngineError::Dropped`.
+    ///
+    /// ## Returns
+    /// Like `disconnect`, whether the connection really has been closed.
+    pub fn close_gracefully(&self, timeout: Duration) -> Future<bool, EngineError> {
+        let (tx, f) = Future::pair();
+        let conn = self.clone();
+
+        // Waiting for the pending sends blocks, so it's done in the
+        // background.
+        thread::spawn(move || {
+            let start = Instant::now();
+            let outgoing = conn.0.lock().expect(STATE_POISONED).outgoing.clone();
+            outgoing.wait_drained(timeout);
+
+            let elapsed = start.elapsed();
+            let remaining = if elapsed < timeout { timeout - elapsed } else { Duration::from_millis(0) };
+            match conn.close(Some(remaining)).await() {
+                Ok(closed) => tx.complete(closed),
+                Err(AsyncError::Failed(err)) => tx.fail(err),
+                Err(AsyncError::Aborted) => tx.abort()
+            }
+        });
+        f
+    }
+
+    /// Closes the transport, gracefully if a timeout is given.
+    fn close(&self, timeout: Option<Duration>) -> Future<bool, EngineError> {
+        self.cancel_reconnect();
+
+        let mut state = self.0.lock().expect(STATE_POISONED);
+        state.heartbeat = None;
+        if let Some(suspended) = state.suspended.take() {
+            drop_pending(suspended);
+        }
+        match (state.transport.take(), timeout) {
+            (Some(transport), Some(timeout)) => transport.close_gracefully(timeout).map(|_| true),
+            (Some(transport), None) => transport.close().map(|_| true),
+            (None, _) => Future::of(false)
         }
     }
 
@@ -112,13 +296,202 @@ impl Connection {
     ///
     /// ## Remarks
     /// The method buffers the packet when one tries to send a
-    /// packet while a connection upgrade is taking place.
+    /// packet while a connection upgrade is taking place or while an
+    /// interrupted session is being resumed.
+    ///
+    /// If the connection is closed before the packets could be sent, the
+    /// future fails with `EngineError::Dropped`, so that they can be sent
+    /// again on the next connection. Once all sent packets have been
+    /// flushed, a `Drain` event is fired.
+    ///
+    /// Sends exceeding the buffer limits block, fail or drop older
+    /// packets according to the configured `OverflowPolicy`.
     pub fn send_all(&self, packets: Vec<Packet>) -> Future<(), EngineError> {
-        if let Some(ref transport) = self.0.lock().expect(STATE_POISONED).transport {
-            transport.send(packets)
-        } else {
-            Future::error(EngineError::invalid_state("Connection was not connected."))
+        let (limits, outgoing) = {
+            let state = self.0.lock().expect(STATE_POISONED);
+            (state.options.buffer_limits.clone(), state.outgoing.clone())
+        };
+        // Reserving may block, so it's done without holding the lock.
+        let amount = Amount::of(&packets);
+        if let Err(err) = outgoing.reserve(amount, &limits) {
+            return Future::error(err);
+        }
+
+        // The future is tracked outside of the lock since it may already
+        // be resolved, which would call the callback right away.
+        let f = self.0.lock().expect(STATE_POISONED).send(packets);
+        match f {
+            Some(f) => outgoing.track(f, amount),
+            None => {
+                outgoing.release(amount);
+                Future::error(EngineError::invalid_state("Connection was not connected."))
+            }
+        }
+    }
+
+    /// Gets the protocol version spoken by the connection.
+    pub fn protocol(&self) -> Protocol {
+        self.0.lock().expect(STATE_POISONED).options.protocol
+    }
+
+    /// Sets the limits of the buffer of packets waiting to be sent.
+    ///
+    /// Default: unlimited.
+    pub fn set_buffer_limits(&self, limits: BufferLimits) {
+        self.0.lock().expect(STATE_POISONED).options.buffer_limits = limits;
+    }
+
+    /// Sets whether binary data is always sent base64-encoded over
+    /// polling instead of using the binary payload encoding.
+    ///
+    /// The binary encoding saves about a third of the bandwidth for binary
+    /// data and is used by default where the protocol supports it. Takes
+    /// effect on the next connect.
+    pub fn set_force_base64(&self, force_base64: bool) {
+        self.0.lock().expect(STATE_POISONED).options.force_base64 = force_base64;
+    }
+
+    /// Gets the policy used to reconnect automatically, if any.
+    pub fn reconnect_policy(&self) -> Option<ReconnectPolicy> {
+        self.0.lock().expect(STATE_POISONED).reconnect_policy.clone()
+    }
+
+    /// Sets the policy used to reconnect automatically after the
+    /// connection has been lost due to an error.
+    ///
+    /// Pass `None` (the default) to disable automatic reconnection.
+    pub fn set_reconnect_policy(&self, policy: Option<ReconnectPolicy>) {
+        self.0.lock().expect(STATE_POISONED).reconnect_policy = policy;
+    }
+
+    /// Sets whether the connection goes straight to a websocket once an
+    /// upgrade to the same host has succeeded before.
+    ///
+    /// If the websocket can't be opened anymore, the connection falls back
+    /// to polling. Takes effect on the next connect.
+    pub fn set_remember_upgrade(&self, remember_upgrade: bool) {
+        self.0.lock().expect(STATE_POISONED).remember_upgrade = remember_upgrade;
+    }
+
+    /// Sets the transports the connection may use in the order of
+    /// preference.
+    ///
+    /// By default, the connection starts out on polling and upgrades to
+    /// websocket. If websocket comes first, the connection is set up
+    /// through a websocket right away and only falls back to polling if
+    /// polling is allowed as well. Takes effect on the next connect.
+    ///
+    /// ## Returns
+    /// An error, if the list is empty.
+    pub fn set_transports(&self, transports: Vec<TransportType>) -> Result<(), EngineError> {
+        if transports.is_empty() {
+            return Err(EngineError::invalid_config(TRANSPORTS_EMPTY));
         }
+        self.0.lock().expect(STATE_POISONED).transports = transports;
+        Ok(())
+    }
+
+    /// Sets whether the connection is set up through a websocket right
+    /// away, skipping the polling handshake and the upgrade.
+    ///
+    /// This saves a few round trips, but only works where websockets can't
+    /// be blocked. Takes effect on the next connect.
+    pub fn set_websocket_only(&self, websocket_only: bool) {
+        let transports = if websocket_only {
+            vec![TransportType::WebSocket]
+        } else {
+            vec![TransportType::Polling, TransportType::WebSocket]
+        };
+        self.0.lock().expect(STATE_POISONED).transports = transports;
+    }
+
+    /// Sets how long the handshake may take before it is given up.
+    ///
+    /// Default: 5 seconds. Takes effect on the next connect.
+    pub fn set_handshake_timeout(&self, timeout: Duration) {
+        self.0.lock().expect(STATE_POISONED).options.handshake_timeout = timeout;
+    }
+
+    /// Sets additional HTTP headers sent with every request, including
+    /// the websocket handshake.
+    ///
+    /// Takes effect on the next connect, which includes automatic
+    /// reconnects.
+    pub fn set_headers(&self, headers: Headers) {
+        self.0.lock().expect(STATE_POISONED).options.headers = headers;
+    }
+
+    /// Sets how the server is reached, i.e. whether and which HTTP proxy
+    /// is used.
+    ///
+    /// Default: `ProxyConfig::Environment`. Takes effect on the next connect.
+    pub fn set_proxy(&self, proxy: ProxyConfig) {
+        self.0.lock().expect(STATE_POISONED).options.proxy = proxy;
+    }
+
+    /// Sets additional query parameters sent with every request.
+    ///
+    /// Takes effect on the next connect.
+    pub fn set_query(&self, query: Vec<(String, String)>) {
+        self.0.lock().expect(STATE_POISONED).options.query = query;
+    }
+
+    /// Sets the read and write timeout of the HTTP requests.
+    ///
+    /// Default: 5 seconds. Takes effect on the next connect.
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        self.0.lock().expect(STATE_POISONED).options.request_timeout = timeout;
+    }
+
+    /// Sets how long the polling transport gathers packets before
+    /// sending them in a single request. Packets queued up while a
+    /// request is in flight are always batched.
+    ///
+    /// Default: 0, i.e. packets are sent right away. Takes effect on
+    /// the next connect.
+    pub fn set_linger(&self, linger: Duration) {
+        self.0.lock().expect(STATE_POISONED).options.linger = linger;
+    }
+
+    /// Sets the maximum size of the packet payloads batched into a
+    /// single polling request in bytes. A send exceeding the size on
+    /// its own is still sent in a request of its own.
+    ///
+    /// Default: `None`, i.e. unlimited. Takes effect on the next connect.
+    pub fn set_max_payload_size(&self, size: Option<usize>) {
+        self.0.lock().expect(STATE_POISONED).options.max_payload_size = size;
+    }
+
+    /// Sets the name of the cache-busting timestamp query parameter.
+    ///
+    /// Default: `t`. Takes effect on the next connect.
+    pub fn set_timestamp_param(&self, name: String) {
+        self.0.lock().expect(STATE_POISONED).options.timestamp_param = name;
+    }
+
+    /// Sets the number of threads doing the HTTP requests of the polling
+    /// transport. A single thread makes sends wait for the running poll,
+    /// more than two aren't used.
+    ///
+    /// Default: 2. Takes effect on the next connect.
+    pub fn set_worker_threads(&self, count: usize) {
+        self.0.lock().expect(STATE_POISONED).options.worker_threads = count;
+    }
+
+    /// Sets the configuration of TLS connections.
+    ///
+    /// Takes effect on the next connect.
+    #[cfg(feature = "ssl")]
+    pub fn set_tls(&self, tls: TlsConfig) {
+        self.0.lock().expect(STATE_POISONED).options.tls = tls;
+    }
+
+    /// Sets whether long polling is done through JSONP instead of XHR.
+    ///
+    /// This is only needed for servers or proxies that don't let XHR
+    /// polling through. Takes effect on the next connect.
+    pub fn set_force_jsonp(&self, force_jsonp: bool) {
+        self.0.lock().expect(STATE_POISONED).options.force_jsonp = force_jsonp;
     }
 
     /// Gets the connection state.
@@ -127,6 +500,24 @@ impl Connection {
         let guard = internal_state.connection_state_lock.read().expect(CONNECTION_STATE_POISONED);
         *guard.deref()
     }
+
+    /// Stops a running automatic reconnection.
+    fn cancel_reconnect(&self) {
+        if let Some(cancelled) = self.0.lock().expect(STATE_POISONED).reconnecting.take() {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A handle of a connection that doesn't keep it alive.
+#[derive(Clone, Debug)]
+pub struct WeakConnection(Weak<Mutex<ConnectionState>>);
+
+impl WeakConnection {
+    /// Gets the connection, if it hasn't been dropped yet.
+    pub fn upgrade(&self) -> Option<Connection> {
+        self.0.upgrade().map(Connection)
+    }
 }
 
 /// Represents the state a connection is in.
@@ -149,24 +540,119 @@ impl Default for State {
 }
 
 struct ConnectionState {
+    callback: Option<SharedCallback>,
     cfg: Option<Config>,
     connection_state_lock: Arc<RwLock<State>>,
-    transport: Option<Polling>,
+    heartbeat: Option<Heartbeat>,
+    options: Options,
+    outgoing: Outgoing,
+    reconnect_policy: Option<ReconnectPolicy>,
+    reconnecting: Option<Arc<AtomicBool>>,
+    remember_upgrade: bool,
+    suspended: Option<Pending>,
+    transport: Option<Box<Transport>>,
+    transports: Vec<TransportType>,
+    upgraded_hosts: HashSet<String>,
     url: Option<Url>
 }
 
+impl ConnectionState {
+    /// Checks whether the given config belongs to the current session.
+    fn is_session(&self, cfg: &Config) -> bool {
+        self.cfg.as_ref().map_or(false, |c| c.sid() == cfg.sid())
+    }
+
+    /// Installs a transport that has been swapped in for the suspended
+    /// one and sends the given pending packets through it, followed by
+    /// the ones sent while the transport was suspended.
+    ///
+    /// If the connection has been closed or reconnected in the meantime,
+    /// the transport is closed instead.
+    fn install(&mut self, transport: Box<Transport>, pending: Pending) {
+        let suspended = match self.suspended.take() {
+            Some(suspended) => suspended,
+            None => {
+                drop_pending(pending);
+                transport.close().fire();
+                return;
+            }
+        };
+        for (packets, tx) in pending.into_iter().chain(suspended) {
+            complete_with(transport.send(packets), tx);
+        }
+        self.transport = Some(transport);
+    }
+
+    /// Checks whether connections to the given URL go straight to a websocket.
+    fn skips_polling(&self, url: &Url) -> bool {
+        match self.transports.first() {
+            Some(&TransportType::WebSocket) => true,
+            _ => {
+                self.remember_upgrade &&
+                self.transports.contains(&TransportType::WebSocket) &&
+                self.upgraded_hosts.contains(&host_key(url))
+            }
+        }
+    }
+
+    /// Sends the given packets through the current transport, or buffers
+    /// them while the transport is suspended.
+    ///
+    /// Returns `None` if there is no connection to send through.
+    fn send(&mut self, packets: Vec<Packet>) -> Option<Future<(), EngineError>> {
+        if let Some(ref transport) = self.transport {
+            return Some(transport.send(packets));
+        }
+        match self.suspended {
+            Some(ref mut buffer) => {
+                let (tx, f) = Future::pair();
+                enqueue(buffer, packets, tx);
+                evict_oldest(buffer, &self.options.buffer_limits);
+                Some(f)
+            },
+            None => None
+        }
+    }
+
+    /// Takes the current transport out of the connection so that it can
+    /// be swapped for another one. Packets sent until the new transport
+    /// is installed are buffered.
+    fn suspend(&mut self) -> Option<Box<Transport>> {
+        let transport = self.transport.take();
+        if transport.is_some() {
+            self.suspended = Some(Vec::new());
+        }
+        transport
+    }
+}
+
 impl Debug for ConnectionState {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         write!(
             formatter,
-            "Connection {{ callback: ..., cfg: {:?}, connection_state: {:?}, transport: {:?}, url: {:?} }}",
-            self.cfg, self.connection_state_lock, self.transport, self.url
+            "Connection {{ callback: ..., cfg: {:?}, connection_state: {:?}, options: {:?}, reconnect_policy: {:?}, remember_upgrade: {:?}, transport: {:?}, transports: {:?}, url: {:?} }}",
+            self.cfg, self.connection_state_lock, self.options, self.reconnect_policy, self.remember_upgrade, self.transport, self.transports, self.url
         )
     }
 }
 
-fn create_connection(url: Url, mut callback: Box<FnMut(EngineEvent) + 'static + Send>, connection_state_lock: Arc<RwLock<State>>) -> Future<Polling, EngineError> {
-    Polling::new(url.clone(), move |ev| {
+/// Sets up a new session through the polling transport.
+fn connect_polling(url: Url, options: Options, callback: Callback) -> Future<(Config, Box<Transport>), EngineError> {
+    if options.force_jsonp {
+        Jsonp::new(url, options, callback)
+            .map(|conn| (conn.cfg().clone(), Box::new(conn) as Box<Transport>))
+    } else {
+        Polling::new(url, options, callback)
+            .map(|conn| (conn.cfg().clone(), Box::new(conn) as Box<Transport>))
+    }
+}
+
+fn create_handler(mut callback: Callback, connection_state_lock: Arc<RwLock<State>>, pulse: Pulse, recovery: Recovery) -> Callback {
+    Box::new(move |ev| {
+        if let EngineEvent::Message(_) = ev {
+            pulse.touch();
+        }
+
         match ev {
             EngineEvent::Connect(c) => callback(EngineEvent::Connect(c)),
             EngineEvent::ConnectError(err) => callback(EngineEvent::ConnectError(err)),
@@ -183,6 +669,7 @@ fn create_connection(url: Url, mut callback: Box<FnMut(EngineEvent) + 'static +
                     *state_val = State::Disconnected;
                 }
                 callback(EngineEvent::Error(err));
+                recovery.reconnect();
             },
             EngineEvent::Message(pck) => {
                 match pck.opcode() {
@@ -194,13 +681,359 @@ fn create_connection(url: Url, mut callback: Box<FnMut(EngineEvent) + 'static +
                         callback(EngineEvent::Disconnect);
                     },
                     OpCode::Message | OpCode::Pong => callback(EngineEvent::Message(pck)),
-                    OpCode::Noop => {},
+
+                    // Server-side pings are answered by the transports.
+                    OpCode::Noop | OpCode::Ping => {},
                     o @ OpCode::Open |
-                    o @ OpCode::Ping |
                     o @ OpCode::Upgrade => unreachable!("Given opcode {:?} should never reach the connection struct.", o)
                 }
             },
             _ => unreachable!()
         }
     })
-}
\ No newline at end of file
+}
+
+/// Starts the heartbeat of a freshly connected session.
+///
+/// When the server fails to answer a ping in time, the transport is
+/// shut down and an `EngineError::Timeout` is emitted.
+fn start_heartbeat(state: &Arc<Mutex<ConnectionState>>, cfg: &Config, protocol: Protocol, pulse: Pulse, signals: Receiver<Signal>, callback: SharedCallback) {
+    if cfg.ping_interval() == Duration::from_millis(0) {
+        return;
+    }
+
+    let ping_state = Arc::downgrade(state);
+    let timeout_state = Arc::downgrade(state);
+    let timeout_cfg = cfg.clone();
+    let heartbeat = Heartbeat::start(cfg, protocol, pulse, signals, move |packet| {
+        send_through(&ping_state, packet)
+    }, move || {
+        if let Some(state) = timeout_state.upgrade() {
+            let transport = {
+                let mut state = state.lock().expect(STATE_POISONED);
+                if !state.is_session(&timeout_cfg) {
+                    return;
+                }
+                state.heartbeat = None;
+                if let Some(suspended) = state.suspended.take() {
+                    drop_pending(suspended);
+                }
+                state.transport.take()
+            };
+            if let Some(transport) = transport {
+                transport.release().receive(|res| {
+                    if let Ok(pending) = res {
+                        drop_pending(pending);
+                    }
+                });
+            }
+        }
+
+        emit(&callback, EngineEvent::Error(EngineError::Timeout));
+    });
+
+    state.lock().expect(STATE_POISONED).heartbeat = Some(heartbeat);
+}
+
+/// Sends a packet through the current transport of the connection.
+///
+/// Returns `false` if there is no connection to send through anymore.
+fn send_through(state: &Weak<Mutex<ConnectionState>>, packet: Packet) -> bool {
+    match state.upgrade() {
+        Some(state) => {
+            match state.lock().expect(STATE_POISONED).send(vec![packet]) {
+                Some(f) => {
+                    f.fire();
+                    true
+                },
+                None => false
+            }
+        },
+        None => false
+    }
+}
+
+/// Completes the given handle once the future has been resolved.
+fn complete_with(f: Future<(), EngineError>, tx: Complete<(), EngineError>) {
+    f.receive(|res| {
+        match res {
+            Ok(_) => tx.complete(()),
+            Err(AsyncError::Failed(err)) => tx.fail(err),
+            Err(AsyncError::Aborted) => tx.abort()
+        }
+    });
+}
+
+/// Checks that the URL can be connected to and maps websocket URLs to
+/// the matching HTTP scheme used for polling.
+fn normalize_url(mut url: Url) -> Result<Url, EngineError> {
+    if url.cannot_be_a_base() {
+        return Err(EngineError::invalid_url(URL_CANNOT_BE_A_BASE));
+    }
+    let scheme = match url.scheme() {
+        "http" | "ws" => "http",
+        "https" | "wss" => "https",
+        _ => return Err(EngineError::invalid_url(URL_SCHEME_INVALID))
+    };
+    let _ = url.set_scheme(scheme);
+    if url.path().is_empty() {
+        return Err(EngineError::invalid_url(URL_PATH_EMPTY));
+    }
+    Ok(url)
+}
+
+/// Identifies the host of the given URL for remembering upgrades.
+fn host_key(url: &Url) -> String {
+    format!("{}:{}", url.host_str().unwrap_or(""), url.port_or_known_default().unwrap_or(0))
+}
+
+fn emit(callback: &SharedCallback, ev: EngineEvent) {
+    let mut guard = callback.lock().expect(CALLBACK_POISONED);
+    guard.deref_mut()(ev);
+}
+
+fn forward_to(callback: &SharedCallback) -> Callback {
+    let callback = callback.clone();
+    Box::new(move |ev| emit(&callback, ev))
+}
+
+/// Reconnects to the given URL in the background according to the
+/// reconnection policy of the connection, if there is one.
+///
+/// This never locks the connection state on the calling thread since
+/// it is invoked from within the transports' callbacks.
+fn reconnect(state: Weak<Mutex<ConnectionState>>, url: Url, callback: SharedCallback) {
+    thread::spawn(move || {
+        let (policy, cancelled) = match state.upgrade() {
+            Some(state) => {
+                let mut state = state.lock().expect(STATE_POISONED);
+                let policy = match state.reconnect_policy.clone() {
+                    Some(policy) => policy,
+                    None => return
+                };
+                let cancelled = Arc::new(AtomicBool::new(false));
+                if let Some(previous) = mem::replace(&mut state.reconnecting, Some(cancelled.clone())) {
+                    previous.store(true, Ordering::SeqCst);
+                }
+                (policy, cancelled)
+            },
+            None => return
+        };
+
+        let mut attempt = 0;
+        loop {
+            if policy.max_attempts.map_or(false, |max| attempt >= max) {
+                emit(&callback, EngineEvent::ReconnectFailed);
+                return;
+            }
+            thread::sleep(policy.delay(attempt));
+            attempt += 1;
+
+            let conn = match state.upgrade() {
+                Some(state) => Connection(state),
+                None => return
+            };
+            if cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            emit(&callback, EngineEvent::ReconnectAttempt(attempt));
+            match conn.connect_shared(url.clone(), callback.clone()).await() {
+                Ok(_) => {
+                    // The user may have disconnected while we were connecting.
+                    if cancelled.load(Ordering::SeqCst) {
+                        conn.disconnect().fire();
+                    } else {
+                        emit(&callback, EngineEvent::Reconnect(attempt));
+                    }
+                    return;
+                },
+                Err(AsyncError::Failed(err)) => emit(&callback, EngineEvent::ConnectError(err)),
+                Err(AsyncError::Aborted) => {}
+            }
+        }
+    });
+}
+
+/// Recovers the connection from transport errors.
+///
+/// All recovery runs on a background thread since it is triggered
+/// from within the transports' callbacks, where the connection state
+/// must not be locked.
+#[derive(Clone)]
+struct Recovery {
+    callback: SharedCallback,
+    state: Weak<Mutex<ConnectionState>>,
+    url: Url
+}
+
+impl Recovery {
+    /// Sets up a new session after the server rejected the current one.
+    ///
+    /// If that fails, the session is lost and the error is reported as
+    /// if it had been emitted by the transport.
+    fn handshake(&self) {
+        let recovery = self.clone();
+        thread::spawn(move || {
+            let conn = match recovery.state.upgrade() {
+                Some(state) => Connection(state),
+                None => return
+            };
+            if let Err(AsyncError::Failed(err)) = conn.connect_shared(recovery.url.clone(), recovery.callback.clone()).await() {
+                let handler = conn.0.lock().expect(STATE_POISONED).callback.clone();
+                if let Some(handler) = handler {
+                    emit(&handler, EngineEvent::Error(err));
+                }
+            }
+        });
+    }
+
+    /// Reconnects according to the reconnection policy, if there is one.
+    fn reconnect(&self) {
+        reconnect(self.state.clone(), self.url.clone(), self.callback.clone());
+    }
+
+    /// Resumes the current session on a new polling transport built from
+    /// the stored config, after the current one has been interrupted by
+    /// network issues.
+    ///
+    /// The packets the interrupted transport couldn't send are sent
+    /// through the new one first, followed by the ones sent in between.
+    fn resume(&self) {
+        let recovery = self.clone();
+        thread::spawn(move || {
+            let state = match recovery.state.upgrade() {
+                Some(state) => state,
+                None => return
+            };
+            let (interrupted, cfg, options, handler) = {
+                let mut state = state.lock().expect(STATE_POISONED);
+                let (cfg, handler) = match (state.cfg.clone(), state.callback.clone()) {
+                    (Some(cfg), Some(handler)) => (cfg, handler),
+                    _ => return
+                };
+                match state.suspend() {
+                    Some(transport) => (transport, cfg, state.options.clone(), handler),
+                    None => return
+                }
+            };
+
+            let pending = interrupted.release().await().unwrap_or_else(|_| Vec::new());
+            let callback = resumable(recovery.clone(), false, forward_to(&handler));
+            let transport: Box<Transport> = if options.force_jsonp {
+                Box::new(Jsonp::with_cfg(recovery.url.clone(), options, callback, cfg))
+            } else {
+                Box::new(Polling::with_cfg(recovery.url.clone(), options, callback, cfg))
+            };
+            state.lock().expect(STATE_POISONED).install(transport, pending);
+        });
+    }
+}
+
+/// Wraps the callback of a polling transport so that network errors
+/// resume the session instead of ending it.
+///
+/// A resumed transport stays unconfirmed until something has been
+/// received through it. If the server rejects the session until then,
+/// a new session is set up. Another network error ends the session.
+fn resumable(recovery: Recovery, mut is_confirmed: bool, mut callback: Callback) -> Callback {
+    Box::new(move |ev| {
+        match ev {
+            EngineEvent::Error(err) => {
+                match (is_confirmed, err) {
+                    (true, ref err) if err.is_transient() => recovery.resume(),
+                    (false, EngineError::Status(_)) => recovery.handshake(),
+                    (_, err) => callback(EngineEvent::Error(err))
+                }
+            },
+            EngineEvent::Message(pck) => {
+                is_confirmed = true;
+                callback(EngineEvent::Message(pck));
+            },
+            ev => callback(ev)
+        }
+    })
+}
+
+/// Probes a websocket for the given session and, if the probe
+/// succeeds, upgrades the connection to it.
+///
+/// The polling transport is paused first so that no more packets
+/// are sent through it. Packets buffered by the polling transport in
+/// the meantime are sent through the websocket after the upgrade.
+/// If anything goes wrong, the connection just stays on polling.
+/// Successful upgrades are remembered per host.
+fn upgrade(state: Arc<Mutex<ConnectionState>>, url: Url, options: Options, cfg: Config, callback: SharedCallback) {
+    let host = host_key(&url);
+    Socket::probe(url, options, forward_to(&callback), cfg.clone()).receive(move |res| {
+        let socket = match res {
+            Ok(socket) => socket,
+            Err(_) => return
+        };
+
+        let pause_f = {
+            let state = state.lock().expect(STATE_POISONED);
+            match state.transport {
+                Some(ref transport) if state.is_session(&cfg) => transport.pause(),
+                _ => return
+            }
+        };
+        if pause_f.await().is_err() {
+            return;
+        }
+
+        let polling = {
+            let mut state = state.lock().expect(STATE_POISONED);
+            if !state.is_session(&cfg) {
+                return;
+            }
+            match state.suspend() {
+                Some(transport) => transport,
+                None => return
+            }
+        };
+        if socket.upgrade().is_err() {
+            polling.start().fire();
+            state.lock().expect(STATE_POISONED).install(polling, Vec::new());
+            return;
+        }
+
+        // The lock must not be held here, the polling transport may still
+        // be delivering packets to handlers that send through the connection.
+        let pending = polling.release().await().unwrap_or_else(|_| Vec::new());
+        let mut state = state.lock().expect(STATE_POISONED);
+        state.upgraded_hosts.insert(host);
+        state.install(Box::new(socket), pending);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize_url, Connection};
+    use transports::TransportType;
+    use url::Url;
+
+    fn normalize(url: &str) -> Result<String, ()> {
+        normalize_url(Url::parse(url).unwrap()).map(|url| url.into_string()).map_err(|_| ())
+    }
+
+    #[test]
+    fn normalize_websocket_schemes() {
+        assert_eq!(normalize("ws://localhost:3000/engine.io/"), Ok("http://localhost:3000/engine.io/".to_owned()));
+        assert_eq!(normalize("wss://example.com/engine.io/"), Ok("https://example.com/engine.io/".to_owned()));
+        assert_eq!(normalize("https://example.com/"), Ok("https://example.com/".to_owned()));
+    }
+
+    #[test]
+    fn normalize_invalid() {
+        assert!(normalize("ftp://example.com/").is_err());
+        assert!(normalize("mailto:someone@example.com").is_err());
+    }
+
+    #[test]
+    fn transports_empty() {
+        let conn = Connection::new();
+        assert!(conn.set_transports(Vec::new()).is_err());
+        assert!(conn.set_transports(vec![TransportType::WebSocket]).is_ok());
+    }
+}
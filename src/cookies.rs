@@ -0,0 +1,146 @@
+//! Contains the cookie jar.
+//!
+//! Load balancers often pin a session to one backend node through a
+//! cookie. The jar collects the cookies of every response and sends
+//! them back with every request of the session, so that all requests
+//! land on the same node.
+
+use std::collections::BTreeMap;
+use std::str;
+use std::sync::{Arc, Mutex};
+use hyper::header::Headers;
+
+const JAR_POISONED: &'static str = "Failed to lock the cookie jar.";
+
+/// A jar of cookies shared by all transports of a connection.
+///
+/// Clones of a jar share the same cookies. Cookie attributes like
+/// `Domain` or `Path` are ignored since a connection only ever talks
+/// to a single endpoint.
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar(Arc<Mutex<BTreeMap<String, String>>>);
+
+impl CookieJar {
+    /// Creates a new, empty cookie jar.
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    /// Removes all cookies from the jar.
+    pub fn clear(&self) {
+        self.0.lock().expect(JAR_POISONED).clear();
+    }
+
+    /// Gets a list of all cookies in the jar as name-value pairs.
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.0.lock().expect(JAR_POISONED).iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Gets the value of the cookie with the given name.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.0.lock().expect(JAR_POISONED).get(name).cloned()
+    }
+
+    /// Removes the cookie with the given name.
+    pub fn remove(&self, name: &str) -> Option<String> {
+        self.0.lock().expect(JAR_POISONED).remove(name)
+    }
+
+    /// Sets a cookie, replacing the one with the same name.
+    pub fn set<K: Into<String>, V: Into<String>>(&self, name: K, value: V) {
+        self.0.lock().expect(JAR_POISONED).insert(name.into(), value.into());
+    }
+
+    /// Adds the `Cookie` header for the cookies in the jar to the given
+    /// headers.
+    pub fn add_to(&self, headers: &mut Headers) {
+        if let Some(value) = self.header_value() {
+            headers.set_raw("Cookie", vec![value.into_bytes()]);
+        }
+    }
+
+    /// Gets the value of the `Cookie` header for the cookies in the jar,
+    /// if there are any.
+    pub fn header_value(&self) -> Option<String> {
+        let jar = self.0.lock().expect(JAR_POISONED);
+        if jar.is_empty() {
+            return None;
+        }
+        let pairs = jar.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>();
+        Some(pairs.join("; "))
+    }
+
+    /// Stores the cookies of the `Set-Cookie` headers of a response.
+    pub fn store_from(&self, headers: &Headers) {
+        if let Some(values) = headers.get_raw("Set-Cookie") {
+            for value in values {
+                self.store(value);
+            }
+        }
+    }
+
+    /// Stores the cookie of a single `Set-Cookie` header value.
+    ///
+    /// Cookies that are expired through `Max-Age` are removed.
+    pub fn store(&self, set_cookie: &[u8]) {
+        if let Some((name, value, is_expired)) = str::from_utf8(set_cookie).ok().and_then(parse) {
+            let mut jar = self.0.lock().expect(JAR_POISONED);
+            if is_expired {
+                jar.remove(&name);
+            } else {
+                jar.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Parses a `Set-Cookie` header value into the name and the value of
+/// the cookie and whether the cookie is expired.
+fn parse(set_cookie: &str) -> Option<(String, String, bool)> {
+    let mut parts = set_cookie.split(';');
+    let pair = parts.next().unwrap_or("");
+    let (name, value) = match pair.find('=') {
+        Some(index) => (pair[..index].trim(), pair[index + 1..].trim()),
+        None => return None
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    let is_expired = parts.any(|attr| {
+        let attr = attr.trim().to_lowercase();
+        attr.starts_with("max-age=") && attr[8..].parse::<i64>().map(|age| age <= 0).unwrap_or(false)
+    });
+    Some((name.to_owned(), value.to_owned(), is_expired))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn store() {
+        let jar = CookieJar::new();
+        jar.store(b"io=abc123; Path=/; HttpOnly");
+        jar.store(b"AWSALB=node-1; Expires=Tue, 01 Jan 2030 00:00:00 GMT; Path=/");
+        assert_eq!(jar.get("io"), Some("abc123".to_owned()));
+        assert_eq!(jar.header_value(), Some("AWSALB=node-1; io=abc123".to_owned()));
+    }
+
+    #[test]
+    fn store_expired() {
+        let jar = CookieJar::new();
+        jar.set("io", "abc123");
+        jar.store(b"io=; Max-Age=0");
+        assert_eq!(jar.get("io"), None);
+        assert_eq!(jar.header_value(), None);
+    }
+
+    #[test]
+    fn store_invalid() {
+        let jar = CookieJar::new();
+        jar.store(b"no pair here");
+        jar.store(b"=value");
+        assert!(jar.cookies().is_empty());
+    }
+}
@@ -2,8 +2,11 @@ use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::Error as IoError;
 use std::str::Utf8Error;
+use std::sync::Arc;
 use ::Void;
 use hyper::Error as HttpError;
+use hyper::status::StatusCode;
+use packet::Packet;
 use rustc_serialize::base64::FromBase64Error;
 use rustc_serialize::json::DecoderError;
 use ws::{Error as WsError, ErrorKind as WsErrorKind};
@@ -11,17 +14,41 @@ use ws::{Error as WsError, ErrorKind as WsErrorKind};
 /// The error type for engine.io associated operations.
 #[derive(Debug)]
 pub enum EngineError {
+    /// The send buffer was full.
+    ///
+    /// Returned by sends exceeding the `BufferLimits` with the
+    /// `OverflowPolicy::Backpressure`.
+    Backpressure,
+
     /// An error occured while parsing the base-64 encoded binary data.
     Base64(FromBase64Error),
 
     /// An error occured while decoding JSON data.
     Decode(DecoderError),
 
+    /// The packets were dropped because the connection was closed
+    /// before they could be sent.
+    ///
+    /// The packets are handed back so that they can be sent again
+    /// once a new connection has been established.
+    Dropped(Vec<Packet>),
+
     /// An HTTP error occured.
     ///
     /// For example, the server sent an invalid status code.
     Http(HttpError),
 
+    /// The configuration was invalid.
+    ///
+    /// For example, a `ClientBuilder` was told not to use any transport.
+    InvalidConfig(Box<Error + Send + Sync>),
+
+    /// The URL to connect to was invalid.
+    ///
+    /// For example, it had a scheme other than `http`, `https`, `ws`
+    /// or `wss`.
+    InvalidUrl(Box<Error + Send + Sync>),
+
     /// The action could not be performed because the component was in
     /// an invalid state.
     ///
@@ -34,6 +61,24 @@ pub enum EngineError {
     /// For example, the server unexpectedly closed the connection.
     Io(IoError),
 
+    /// An error shared by several failed operations.
+    ///
+    /// For example, all sends that went into a POST request that failed
+    /// fail with the error of the request.
+    Shared(Arc<EngineError>),
+
+    /// The server answered a request with an unsuccessful HTTP status.
+    ///
+    /// For example, the server responds with `400 Bad Request` when it
+    /// doesn't know the session ID anymore.
+    Status(StatusCode),
+
+    /// The other endpoint did not respond in time.
+    ///
+    /// For example, the server did not answer a ping within the
+    /// ping timeout of the session.
+    Timeout,
+
     /// An error occured while parsing string data from UTF-8.
     Utf8,
 
@@ -45,6 +90,12 @@ pub enum EngineError {
 }
 
 impl EngineError {
+    /// Creates an `EngineError::InvalidConfig` variant. Mainly
+    /// used in combination with string literals.
+    pub fn invalid_config<E: Into<Box<Error + Send + Sync>>>(err: E) -> EngineError {
+        EngineError::InvalidConfig(err.into())
+    }
+
     /// Creates an `EngineError::InvalidState` variant. Mainly
     /// used in combination with string literals.
     ///
@@ -57,6 +108,12 @@ impl EngineError {
         EngineError::InvalidState(err.into())
     }
 
+    /// Creates an `EngineError::InvalidUrl` variant. Mainly
+    /// used in combination with string literals.
+    pub fn invalid_url<E: Into<Box<Error + Send + Sync>>>(err: E) -> EngineError {
+        EngineError::InvalidUrl(err.into())
+    }
+
     /// Tries to get the underlying I/O error, if one is present.
     ///
     /// Since this error combines errors from multiple sources,
@@ -73,9 +130,16 @@ impl EngineError {
                     None
                 }
             }
+            EngineError::Shared(ref err) => err.io(),
             _ => None
         }
     }
+
+    /// Checks whether the error is a transient network error, i.e.
+    /// whether retrying the failed operation may succeed.
+    pub fn is_transient(&self) -> bool {
+        self.io().is_some()
+    }
 }
 
 impl Display for EngineError {
@@ -87,11 +151,18 @@ impl Display for EngineError {
 impl Error for EngineError {
     fn description(&self) -> &str {
         match *self {
+            EngineError::Backpressure => "The send buffer was full.",
             EngineError::Base64(ref err) => err.description(),
             EngineError::Decode(ref err) => err.description(),
+            EngineError::Dropped(_) => "The packets were dropped due to a disconnect.",
             EngineError::Http(ref err) => err.description(),
+            EngineError::InvalidConfig(ref err) => err.description(),
             EngineError::InvalidState(ref err) => err.description(),
+            EngineError::InvalidUrl(ref err) => err.description(),
             EngineError::Io(ref err) => err.description(),
+            EngineError::Shared(ref err) => err.description(),
+            EngineError::Status(_) => "The server answered with an unsuccessful status code.",
+            EngineError::Timeout => "The other endpoint did not respond in time.",
             EngineError::Utf8 => "UTF-8 data was invalid.",
             EngineError::WebSocket(ref err) => err.description(),
             _ => "Unknown engine.io error."
@@ -100,11 +171,18 @@ impl Error for EngineError {
 
     fn cause(&self) -> Option<&Error> {
         match *self {
+            EngineError::Backpressure => None,
             EngineError::Base64(ref err) => Some(err),
             EngineError::Decode(ref err) => Some(err),
+            EngineError::Dropped(_) => None,
             EngineError::Http(ref err) => Some(err),
+            EngineError::InvalidConfig(ref err) => err.cause(),
             EngineError::InvalidState(ref err) => err.cause(),
+            EngineError::InvalidUrl(ref err) => err.cause(),
             EngineError::Io(ref err) => Some(err),
+            EngineError::Shared(ref err) => err.cause(),
+            EngineError::Status(_) => None,
+            EngineError::Timeout => None,
             EngineError::Utf8 => None,
             EngineError::WebSocket(ref err) => Some(err),
             _ => None
@@ -0,0 +1,200 @@
+//! The client-side heartbeat.
+//!
+//! In protocol version 3, engine.io expects the client to send a ping
+//! every `pingInterval` milliseconds. If the server doesn't answer the
+//! ping (or send anything else) within `pingTimeout` milliseconds, the
+//! session is considered dead.
+//!
+//! In protocol version 4 the roles are reversed: the server sends the
+//! pings (which are answered by the transports) and the session is
+//! considered dead if nothing has been received for `pingInterval +
+//! pingTimeout` milliseconds.
+
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use ::{OpCode, Packet, Protocol};
+use transports::Config;
+
+/// Creates a new pulse and the receiving end the heartbeat is
+/// started with.
+pub fn pulse() -> (Pulse, Receiver<Signal>) {
+    let (tx, rx) = channel();
+    (Pulse(tx), rx)
+}
+
+/// A signal sent to the heartbeat thread.
+#[derive(Debug)]
+pub enum Signal {
+    /// Traffic has been received from the server.
+    Activity,
+
+    /// The heartbeat shall stop.
+    Stop
+}
+
+/// Notifies the heartbeat about traffic from the server.
+#[derive(Clone, Debug)]
+pub struct Pulse(Sender<Signal>);
+
+impl Pulse {
+    /// Marks the session as alive.
+    pub fn touch(&self) {
+        let _ = self.0.send(Signal::Activity);
+    }
+}
+
+/// A running heartbeat. The heartbeat is stopped when this is dropped.
+#[derive(Debug)]
+pub struct Heartbeat(Pulse);
+
+impl Heartbeat {
+    /// Starts a new heartbeat on a background thread.
+    ///
+    /// ## Parameters
+    /// - `cfg: &Config`: The session config containing the ping
+    ///   interval and timeout.
+    /// - `protocol: Protocol`: The protocol version of the session.
+    /// - `pulse: Pulse`: The pulse whose receiving end is `signals`.
+    /// - `signals: Receiver<Signal>`: Receives traffic notifications.
+    /// - `ping: P`: Sends the given ping packet to the server. Returns
+    ///   `false` if the session is gone and the heartbeat should stop.
+    /// - `on_timeout: T`: Called when the server didn't answer in time.
+    pub fn start<P, T>(cfg: &Config, protocol: Protocol, pulse: Pulse, signals: Receiver<Signal>, mut ping: P, on_timeout: T) -> Heartbeat
+        where P: FnMut(Packet) -> bool + Send + 'static,
+              T: FnOnce() + Send + 'static {
+        let interval = cfg.ping_interval();
+        let timeout = cfg.ping_timeout();
+
+        thread::spawn(move || {
+            if protocol == Protocol::V4 {
+                loop {
+                    match signals.recv_timeout(interval + timeout) {
+                        Ok(Signal::Activity) => {},
+                        Err(RecvTimeoutError::Timeout) => {
+                            on_timeout();
+                            return;
+                        },
+                        _ => return
+                    }
+                }
+            }
+
+            loop {
+                if !wait_for(&signals, interval) {
+                    return;
+                }
+                if !ping(Packet::with_str(OpCode::Ping, "")) {
+                    return;
+                }
+                match signals.recv_timeout(timeout) {
+                    Ok(Signal::Activity) => {},
+                    Err(RecvTimeoutError::Timeout) => {
+                        on_timeout();
+                        return;
+                    },
+                    _ => return
+                }
+            }
+        });
+
+        Heartbeat(pulse)
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        let _ = (self.0).0.send(Signal::Stop);
+    }
+}
+
+/// Waits for the given duration, ignoring any activity in between.
+///
+/// Returns `false` if the heartbeat has been stopped in the meantime.
+fn wait_for(signals: &Receiver<Signal>, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        match signals.recv_timeout(deadline - now) {
+            Ok(Signal::Activity) => {},
+            Err(RecvTimeoutError::Timeout) => return true,
+            _ => return false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustc_serialize::json::decode;
+
+    fn config(interval: u32, timeout: u32) -> Config {
+        decode(&format!(r#"{{"sid":"test","upgrades":[],"pingInterval":{},"pingTimeout":{}}}"#, interval, timeout)).unwrap()
+    }
+
+    #[test]
+    fn ping_cadence() {
+        let (pulse, signals) = pulse();
+        let (ping_tx, ping_rx) = channel();
+        let (timeout_tx, timeout_rx) = channel();
+        let server = pulse.clone();
+        let _heartbeat = Heartbeat::start(&config(20, 1000), Protocol::V3, pulse, signals, move |packet| {
+            // The server answers every ping right away.
+            server.touch();
+            ping_tx.send(packet.opcode()).is_ok()
+        }, move || timeout_tx.send(()).unwrap());
+
+        for _ in 0..3 {
+            assert_eq!(ping_rx.recv_timeout(Duration::from_secs(1)), Ok(OpCode::Ping));
+        }
+        assert_eq!(timeout_rx.try_recv().ok(), None);
+    }
+
+    #[test]
+    fn timeout() {
+        let (pulse, signals) = pulse();
+        let (timeout_tx, timeout_rx) = channel();
+        let _heartbeat = Heartbeat::start(&config(10, 20), Protocol::V3, pulse, signals, |_| true, move || {
+            timeout_tx.send(()).unwrap()
+        });
+
+        assert_eq!(timeout_rx.recv_timeout(Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn touch() {
+        let (pulse, signals) = pulse();
+        let (timeout_tx, timeout_rx) = channel();
+        let server = pulse.clone();
+        let _heartbeat = Heartbeat::start(&config(30, 30), Protocol::V4, pulse, signals, |_| true, move || {
+            timeout_tx.send(()).unwrap()
+        });
+
+        // Traffic from the server keeps the session alive...
+        for _ in 0..20 {
+            server.touch();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(timeout_rx.try_recv().ok(), None);
+
+        // ...until it stops.
+        assert_eq!(timeout_rx.recv_timeout(Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn stop() {
+        let (pulse, signals) = pulse();
+        let (ping_tx, ping_rx) = channel();
+        let (timeout_tx, timeout_rx) = channel();
+        let heartbeat = Heartbeat::start(&config(20, 20), Protocol::V3, pulse, signals, move |_| {
+            ping_tx.send(()).is_ok()
+        }, move || timeout_tx.send(()).unwrap());
+        drop(heartbeat);
+
+        assert_eq!(ping_rx.recv_timeout(Duration::from_millis(100)), Err(RecvTimeoutError::Disconnected));
+        assert_eq!(timeout_rx.try_recv().ok(), None);
+    }
+}
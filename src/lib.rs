@@ -11,9 +11,13 @@
 #![feature(custom_derive, io, mpsc_select)]
 
 extern crate eventual;
+#[cfg(feature = "stream")]
+extern crate futures;
 extern crate hyper;
-#[macro_use]
-extern crate lazy_static;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+#[cfg(feature = "ssl")]
+extern crate openssl_verify;
 extern crate rand;
 extern crate rustc_serialize;
 extern crate threadpool;
@@ -23,14 +27,34 @@ extern crate ws;
 
 mod client;
 mod connection;
+mod cookies;
 mod error;
+mod heartbeat;
+mod outgoing;
 mod packet;
+mod proxy;
+mod reconnect;
+#[cfg(feature = "stream")]
+mod stream;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "ssl")]
+mod tls;
 mod transports;
 
-pub use client::{Client, Registration};
+pub use client::{Client, ClientBuilder, EventReceiver, Incoming, RECEIVER_CAPACITY, Registration, RegistrationGuard};
 pub use connection::Connection;
+pub use cookies::CookieJar;
 pub use error::EngineError;
-pub use packet::{OpCode, Packet, Payload};
+pub use outgoing::{BufferLimits, OverflowPolicy};
+pub use packet::{OpCode, Packet, Payload, Protocol};
+pub use proxy::{Proxy, ProxyConfig};
+pub use reconnect::ReconnectPolicy;
+#[cfg(feature = "stream")]
+pub use stream::{EventStream, PacketSink};
+#[cfg(feature = "ssl")]
+pub use tls::TlsConfig;
+pub use transports::TransportType;
 
 const HANDLER_LOCK_POISONED: &'static str = "Failed to acquire handler callbacks lock.";
 
@@ -46,12 +70,32 @@ pub enum EngineEvent {
     /// Fired when the connection is disconnected.
     Disconnect,
 
+    /// Fired when all packets sent through the connection have been
+    /// flushed.
+    Drain,
+
     /// Fired when the connection is disconnected due to an error.
     Error(EngineError),
 
+    /// Fired when a handler panicked while handling an event. Contains
+    /// the panic message. The handler is unregistered afterwards.
+    HandlerPanic(String),
+
     /// Fired when a message is sent over the connection.
     Message(Packet),
 
+    /// Fired when the connection has been reestablished automatically
+    /// after the given number of attempts.
+    Reconnect(u32),
+
+    /// Fired before every automatic reconnection attempt. Contains the
+    /// number of the attempt, starting at 1.
+    ReconnectAttempt(u32),
+
+    /// Fired when automatic reconnection has been given up because the
+    /// maximum number of attempts has been reached.
+    ReconnectFailed,
+
     #[doc(hidden)]
     __Nonexhaustive(Void)
 }
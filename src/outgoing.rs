@@ -0,0 +1,406 @@
+//! Contains the bookkeeping of the packets on their way to the server.
+//!
+//! Every send through a connection is tracked until its future has
+//! been resolved. Once the last pending send has been flushed
+//! successfully, a `Drain` event is fired.
+//!
+//! The amount of packets and bytes that may be buffered can be limited.
+//! What happens when the limits are exceeded is up to the configured
+//! `OverflowPolicy`.
+
+use std::cell::Cell;
+use std::mem;
+use std::ops::DerefMut;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use ::{EngineError, EngineEvent};
+use connection::SharedCallback;
+use eventual::{Async, AsyncError, Future};
+use packet::{Packet, Payload};
+
+const CALLBACK_POISONED: &'static str = "Failed to lock connection callback.";
+const OUTGOING_POISONED: &'static str = "Failed to lock outgoing packet state.";
+
+thread_local!(static IS_TRANSPORT_THREAD: Cell<bool> = Cell::new(false));
+
+/// Limits the amount of packets buffered for sending.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BufferLimits {
+    /// The maximum number of buffered packets, or `None` for no limit.
+    ///
+    /// Default: `None`.
+    pub max_packets: Option<usize>,
+
+    /// The maximum size of the payloads of the buffered packets in
+    /// bytes, or `None` for no limit.
+    ///
+    /// Default: `None`.
+    pub max_bytes: Option<usize>,
+
+    /// What happens when sending a packet exceeds the limits.
+    ///
+    /// Default: `OverflowPolicy::Backpressure`.
+    pub policy: OverflowPolicy
+}
+
+impl BufferLimits {
+    /// Checks whether the given amount of packets and bytes exceeds
+    /// the limits.
+    pub fn is_exceeded_by(&self, packets: usize, bytes: usize) -> bool {
+        self.max_packets.map_or(false, |max| packets > max) ||
+        self.max_bytes.map_or(false, |max| bytes > max)
+    }
+}
+
+/// Determines what happens when sending a packet exceeds the limits
+/// of the send buffer.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum OverflowPolicy {
+    /// Block the sending thread until enough packets have been sent.
+    ///
+    /// The packets are sent by the threads of the transports, which
+    /// also call the callback of a `Connection`. Sends made from there
+    /// would wait for themselves, so they fail with
+    /// `EngineError::Backpressure` instead. The handlers of a `Client`
+    /// run on a thread of their own and may block.
+    Block,
+
+    /// Fail the send with `EngineError::Backpressure`. This is the
+    /// default.
+    Backpressure,
+
+    /// Drop the oldest packets that are still waiting to be sent. Their
+    /// sends fail with `EngineError::Dropped`. Packets that are being
+    /// sent already are never dropped.
+    DropOldest
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Backpressure
+    }
+}
+
+/// The number of packets of a send and the size of their payloads.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Amount {
+    /// The size of the payloads in bytes.
+    pub bytes: usize,
+
+    /// The number of packets.
+    pub packets: usize
+}
+
+impl Amount {
+    /// Measures the given packets.
+    pub fn of(packets: &[Packet]) -> Amount {
+        let bytes = packets.iter().map(|packet| {
+            match *packet.payload() {
+                Payload::Binary(ref data) => data.len(),
+                Payload::String(ref string) => string.len()
+            }
+        }).sum();
+        Amount {
+            bytes: bytes,
+            packets: packets.len()
+        }
+    }
+}
+
+/// Tracks the sends of a connection that haven't been resolved yet.
+#[derive(Clone)]
+pub struct Outgoing {
+    room: Arc<Condvar>,
+    state: Arc<Mutex<OutgoingState>>
+}
+
+struct OutgoingState {
+    bytes: usize,
+    callback: Option<SharedCallback>,
+    packets: usize,
+    pending: usize,
+    watchers: Vec<Box<FnMut() + Send>>
+}
+
+impl OutgoingState {
+    fn exceeds(&self, amount: Amount, limits: &BufferLimits) -> bool {
+        self.pending > 0 && limits.is_exceeded_by(self.packets + amount.packets, self.bytes + amount.bytes)
+    }
+
+    /// Books the resolution of a send and takes the watchers waiting
+    /// for it.
+    fn resolve(&mut self, amount: Amount) -> Vec<Box<FnMut() + Send>> {
+        self.bytes -= amount.bytes;
+        self.packets -= amount.packets;
+        self.pending -= 1;
+        mem::replace(&mut self.watchers, Vec::new())
+    }
+}
+
+/// Marks the current thread as one of the threads of a transport, on
+/// which sends must not block.
+pub fn mark_transport_thread() {
+    IS_TRANSPORT_THREAD.with(|flag| flag.set(true));
+}
+
+impl Outgoing {
+    /// Creates a new tracker without pending sends.
+    pub fn new() -> Outgoing {
+        Outgoing {
+            room: Arc::new(Condvar::new()),
+            state: Arc::new(Mutex::new(OutgoingState {
+                bytes: 0,
+                callback: None,
+                packets: 0,
+                pending: 0,
+                watchers: Vec::new()
+            }))
+        }
+    }
+
+    /// Gets the size of the payloads of the packets that haven't been
+    /// sent yet in bytes.
+    pub fn buffered_amount(&self) -> usize {
+        self.state.lock().expect(OUTGOING_POISONED).bytes
+    }
+
+    /// Checks whether sending the given packets would exceed the limits.
+    pub fn exceeds(&self, amount: Amount, limits: &BufferLimits) -> bool {
+        self.state.lock().expect(OUTGOING_POISONED).exceeds(amount, limits)
+    }
+
+    /// Calls the given function once the next pending send has been
+    /// resolved, or right away if there is none.
+    pub fn notify_room<F: FnMut() + Send + 'static>(&self, mut f: F) {
+        {
+            let mut state = self.state.lock().expect(OUTGOING_POISONED);
+            if state.pending > 0 {
+                state.watchers.push(Box::new(f));
+                return;
+            }
+        }
+        f();
+    }
+
+    /// Gets the number of sends that haven't been resolved yet.
+    pub fn pending(&self) -> usize {
+        self.state.lock().expect(OUTGOING_POISONED).pending
+    }
+
+    /// Blocks until all pending sends have been resolved or the timeout
+    /// has elapsed, and returns whether they have.
+    pub fn wait_drained(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        let mut state = self.state.lock().expect(OUTGOING_POISONED);
+        while state.pending > 0 {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return false;
+            }
+            state = self.room.wait_timeout(state, timeout - elapsed).expect(OUTGOING_POISONED).0;
+        }
+        true
+    }
+
+    /// Makes room for sending the given packets according to the limits.
+    ///
+    /// A send is always admitted if nothing else is buffered, so that
+    /// packets exceeding the limits on their own can still be sent.
+    pub fn reserve(&self, amount: Amount, limits: &BufferLimits) -> Result<(), EngineError> {
+        let mut state = self.state.lock().expect(OUTGOING_POISONED);
+        while state.exceeds(amount, limits) {
+            match limits.policy {
+                OverflowPolicy::Block if !IS_TRANSPORT_THREAD.with(|flag| flag.get()) => {
+                    state = self.room.wait(state).expect(OUTGOING_POISONED)
+                },
+                OverflowPolicy::Block | OverflowPolicy::Backpressure => return Err(EngineError::Backpressure),
+
+                // The transports drop the packets while buffering them.
+                OverflowPolicy::DropOldest => break
+            }
+        }
+        state.bytes += amount.bytes;
+        state.packets += amount.packets;
+        state.pending += 1;
+        Ok(())
+    }
+
+    /// Gives back the room reserved for packets that haven't been sent.
+    pub fn release(&self, amount: Amount) {
+        let watchers = self.state.lock().expect(OUTGOING_POISONED).resolve(amount);
+        self.room.notify_all();
+        for mut watcher in watchers {
+            watcher();
+        }
+    }
+
+    /// Sets the callback the `Drain` event is fired through.
+    pub fn set_callback(&self, callback: SharedCallback) {
+        self.state.lock().expect(OUTGOING_POISONED).callback = Some(callback);
+    }
+
+    /// Tracks the given send of packets whose room has been reserved
+    /// until it is resolved.
+    ///
+    /// The callback is never called while the lock of the tracker is
+    /// held, so the tracked future may resolve on any thread.
+    pub fn track(&self, f: Future<(), EngineError>, amount: Amount) -> Future<(), EngineError> {
+        let (tx, tracked_f) = Future::pair();
+        let (room, state) = (self.room.clone(), self.state.clone());
+        f.receive(move |res| {
+            let is_ok = res.is_ok();
+            let (callback, watchers) = {
+                let mut state = state.lock().expect(OUTGOING_POISONED);
+                let watchers = state.resolve(amount);
+                room.notify_all();
+                (if is_ok && state.pending == 0 { state.callback.clone() } else { None }, watchers)
+            };
+
+            for mut watcher in watchers {
+                watcher();
+            }
+            match res {
+                Ok(_) => tx.complete(()),
+                Err(AsyncError::Failed(err)) => tx.fail(err),
+                Err(AsyncError::Aborted) => tx.abort()
+            }
+            if let Some(callback) = callback {
+                callback.lock().expect(CALLBACK_POISONED).deref_mut()(EngineEvent::Drain);
+            }
+        });
+        tracked_f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::EngineError;
+    use eventual::{Async, Future};
+    use packet::{OpCode, Packet};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn packets(payload: &str, count: usize) -> Vec<Packet> {
+        vec![Packet::with_str(OpCode::Message, payload); count]
+    }
+
+    #[test]
+    fn amount() {
+        let mut batch = packets("Hello", 2);
+        batch.push(Packet::with_binary(OpCode::Message, vec![1, 2, 3]));
+        assert_eq!(Amount::of(&batch), Amount { bytes: 13, packets: 3 });
+    }
+
+    #[test]
+    fn limits() {
+        let limits = BufferLimits {
+            max_packets: Some(2),
+            max_bytes: Some(10),
+            ..Default::default()
+        };
+        assert!(!limits.is_exceeded_by(2, 10));
+        assert!(limits.is_exceeded_by(3, 0));
+        assert!(limits.is_exceeded_by(0, 11));
+        assert!(!BufferLimits::default().is_exceeded_by(1000, 1000));
+    }
+
+    #[test]
+    fn backpressure() {
+        let outgoing = Outgoing::new();
+        let limits = BufferLimits {
+            max_bytes: Some(8),
+            ..Default::default()
+        };
+
+        // A single send may exceed the limits on its own.
+        let amount = Amount::of(&packets("Hello World", 1));
+        outgoing.reserve(amount, &limits).expect("Failed to send into empty buffer.");
+        let (tx, f) = Future::pair();
+        let tracked_f = outgoing.track(f, amount);
+        assert_eq!(outgoing.buffered_amount(), 11);
+
+        match outgoing.reserve(Amount::of(&packets("Hi", 1)), &limits) {
+            Err(EngineError::Backpressure) => {},
+            res => panic!("Expected backpressure, got {:?}.", res)
+        }
+
+        tx.complete(());
+        tracked_f.await().expect("Tracked send failed.");
+        assert_eq!(outgoing.buffered_amount(), 0);
+        assert_eq!(outgoing.pending(), 0);
+        outgoing.reserve(Amount::of(&packets("Hi", 1)), &limits).expect("Failed to send into drained buffer.");
+    }
+
+    #[test]
+    fn block() {
+        let outgoing = Outgoing::new();
+        let limits = BufferLimits {
+            max_packets: Some(1),
+            policy: OverflowPolicy::Block,
+            ..Default::default()
+        };
+        let amount = Amount::of(&packets("Hello", 1));
+        outgoing.reserve(amount, &limits).expect("Failed to send into empty buffer.");
+
+        // Transport threads would wait for themselves.
+        let (outgoing_c, limits_c) = (outgoing.clone(), limits.clone());
+        let res = thread::spawn(move || {
+            mark_transport_thread();
+            outgoing_c.reserve(amount, &limits_c)
+        }).join().unwrap();
+        match res {
+            Err(EngineError::Backpressure) => {},
+            res => panic!("Expected backpressure, got {:?}.", res)
+        }
+
+        let outgoing_c = outgoing.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            outgoing_c.release(amount);
+        });
+        outgoing.reserve(amount, &limits).expect("Failed to send once room has been made.");
+        assert_eq!(outgoing.pending(), 1);
+    }
+
+    #[test]
+    fn room() {
+        let outgoing = Outgoing::new();
+        let notified = Arc::new(Mutex::new(0));
+        let notified_c = notified.clone();
+        outgoing.notify_room(move || *notified_c.lock().unwrap() += 1);
+        assert_eq!(*notified.lock().unwrap(), 1);
+
+        let amount = Amount::of(&packets("Hello", 1));
+        outgoing.reserve(amount, &BufferLimits::default()).expect("Failed to send into empty buffer.");
+        let (tx, f) = Future::pair();
+        let tracked_f = outgoing.track(f, amount);
+        let notified_c = notified.clone();
+        outgoing.notify_room(move || *notified_c.lock().unwrap() += 1);
+        assert_eq!(*notified.lock().unwrap(), 1);
+
+        tx.fail(EngineError::Timeout);
+        assert!(tracked_f.await().is_err());
+        assert_eq!(*notified.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn drain() {
+        let outgoing = Outgoing::new();
+        assert!(outgoing.wait_drained(Duration::from_millis(0)));
+
+        let amount = Amount::of(&packets("Hello", 1));
+        outgoing.reserve(amount, &BufferLimits::default()).expect("Failed to send into empty buffer.");
+        let (tx, f) = Future::pair();
+        let tracked_f = outgoing.track(f, amount);
+        assert!(!outgoing.wait_drained(Duration::from_millis(10)));
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            tx.complete(());
+        });
+        assert!(outgoing.wait_drained(Duration::from_secs(5)));
+        tracked_f.await().expect("Tracked send failed.");
+    }
+}
@@ -1,8 +1,16 @@
 //! Contains the code for an engine.io packet.
 //!
-//! This implementation only supports the base64 / text encoding
-//! since it is the only one that is implemented in a sane way by
-//! the creators of engine.io.
+//! Packets can be encoded as text (binary data is base64-encoded)
+//! or, for XHR polling in protocol version 3, using the binary payload
+//! encoding. The latter prefixes each packet with a type byte (`0x00`
+//! for string packets, `0x01` for binary ones), the packet length as
+//! one byte per decimal digit and a `0xFF` separator.
+//!
+//! Both revisions of the protocol are supported. Version 3 frames
+//! payloads as `length:packet` and prefixes binary packets with `b`
+//! and their opcode. Version 4 separates the packets of a payload
+//! with the record separator `\x1e`, and binary packets are always
+//! messages encoded as `b` followed by the base64 data.
 
 use std::fmt::{Display, format, Formatter, Result as FmtResult};
 use std::io::{BufRead, CharsError, Error as IoError, ErrorKind, Read, Result as IoResult, Write};
@@ -13,8 +21,11 @@ use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
 use rustc_serialize::json;
 use ws;
 
+const BINARY_TYPE_INVALID: &'static str = "The binary payload contained an invalid packet type byte (must be 0x00 or 0x01).";
 const BUFFER_UNEXPECTED_EOF: &'static str = "Packet opcode or binary indicator could not be read because the end of the buffer string was reached.";
 const DATA_LENGTH_INVALID: &'static str = "The data length could not be parsed.";
+const LENGTH_SEPARATOR: u8 = 0xFF;
+const RECORD_SEPARATOR: char = '\x1e';
 const READER_UNEXPECTED_EOF: &'static str = "Reader reached its end before the packet length could be read.";
 
 /// A macro to efficiently write a packet into a stream.
@@ -25,11 +36,15 @@ const READER_UNEXPECTED_EOF: &'static str = "Reader reached its end before the p
 /// If it were not for this macro, we'd have to implement
 /// the encoding twice, which would be ugly.
 macro_rules! write_packet {
-    ($s:ident, $e:expr) => {{
+    ($s:ident, $e:expr) => {
+        write_packet!($s, $e, Protocol::V3)
+    };
+    ($s:ident, $e:expr, $p:expr) => {{
         let opcode_str = $s.opcode.string_repr();
-        match $s.payload {
-            Payload::Binary(ref data) => write!($e, "b{}{}", opcode_str, data.to_base64(STANDARD)),
-            Payload::String(ref string) => write!($e, "{}{}", opcode_str, string)
+        match (&$s.payload, $p) {
+            (&Payload::Binary(ref data), Protocol::V3) => write!($e, "b{}{}", opcode_str, data.to_base64(STANDARD)),
+            (&Payload::Binary(ref data), Protocol::V4) => write!($e, "b{}", data.to_base64(STANDARD)),
+            (&Payload::String(ref string), _) => write!($e, "{}{}", opcode_str, string)
         }
     }}
 }
@@ -81,6 +96,18 @@ impl Packet {
         Packet::from_str(&buf)
     }
 
+    /// Parses a packet from a string slice in the encoding of the
+    /// given protocol version.
+    pub fn from_str_with(buf: &str, protocol: Protocol) -> Result<Self, EngineError> {
+        match (buf.chars().nth(0), protocol) {
+            (Some('b'), Protocol::V4) => {
+                let b64 = try!(buf[1..].from_base64());
+                Ok(Packet::with_binary(OpCode::Message, b64))
+            },
+            _ => Packet::from_str(buf)
+        }
+    }
+
     /// Parses a list of packets in payload encoding from a `reader`.
     pub fn from_reader_all<R: BufRead>(reader: &mut R) -> Result<Vec<Self>, EngineError> {
         let mut results = Vec::new();
@@ -98,6 +125,36 @@ impl Packet {
         }
     }
 
+    /// Parses a list of packets in binary payload encoding from a `reader`.
+    pub fn from_reader_all_binary<R: BufRead>(reader: &mut R) -> Result<Vec<Self>, EngineError> {
+        let mut results = Vec::new();
+        loop {
+            match Packet::from_reader_payload_binary(reader) {
+                Ok(packet) => results.push(packet),
+                Err(err) => {
+                    return if results.len() > 0 {
+                        Ok(results)
+                    } else {
+                        Err(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses a list of packets in the payload encoding of the given
+    /// protocol version from a `reader`.
+    pub fn from_reader_all_with<R: BufRead>(reader: &mut R, protocol: Protocol) -> Result<Vec<Self>, EngineError> {
+        match protocol {
+            Protocol::V3 => Packet::from_reader_all(reader),
+            Protocol::V4 => {
+                let mut buf = String::new();
+                try!(reader.read_to_string(&mut buf));
+                buf.split(RECORD_SEPARATOR).map(|p| Packet::from_str_with(p, protocol)).collect()
+            }
+        }
+    }
+
     /// Tries to parse a packet in payload encoding from a `reader`.
     /// Only the data needed is read from the data source.
     pub fn from_reader_payload<R: BufRead>(reader: &mut R) -> Result<Self, EngineError> {
@@ -121,6 +178,41 @@ impl Packet {
         Packet::from_str(&string)
     }
 
+    /// Tries to parse a packet in binary payload encoding from a `reader`.
+    /// Only the data needed is read from the data source.
+    pub fn from_reader_payload_binary<R: BufRead>(reader: &mut R) -> Result<Self, EngineError> {
+        let mut buf = Vec::with_capacity(8);
+        if try!(reader.read_until(LENGTH_SEPARATOR, &mut buf)) == 0 {
+            return Err(IoError::new(ErrorKind::UnexpectedEof, READER_UNEXPECTED_EOF).into());
+        }
+        if buf.len() < 3 || buf[buf.len() - 1] != LENGTH_SEPARATOR {
+            return Err(EngineError::Io(IoError::new(ErrorKind::InvalidData, DATA_LENGTH_INVALID)));
+        }
+
+        let is_string = match buf[0] {
+            0 => true,
+            1 => false,
+            _ => return Err(EngineError::Io(IoError::new(ErrorKind::InvalidData, BINARY_TYPE_INVALID)))
+        };
+        let mut data_length = 0usize;
+        for &digit in &buf[1..buf.len() - 1] {
+            data_length = try!(data_length.checked_mul(10)
+                                          .and_then(|l| if digit < 10 { l.checked_add(digit as usize) } else { None })
+                                          .ok_or(EngineError::Io(IoError::new(ErrorKind::InvalidData, DATA_LENGTH_INVALID))));
+        }
+
+        let mut data = vec![0; data_length];
+        try!(reader.read_exact(&mut data));
+        if is_string {
+            Packet::from_str(try!(from_utf8(&data)))
+        } else {
+            match data.split_first() {
+                Some((&opcode, data)) => Ok(Packet::with_binary(try!(OpCode::from_u8(opcode)), data.to_vec())),
+                None => Err(EngineError::Io(IoError::new(ErrorKind::UnexpectedEof, BUFFER_UNEXPECTED_EOF)))
+            }
+        }
+    }
+
     /// Gets the opcode.
     pub fn opcode(&self) -> OpCode {
         self.opcode
@@ -153,6 +245,51 @@ impl Packet {
         write_packet!(self, writer)
     }
 
+    /// Writes the packet into the given `writer` using the encoding
+    /// of the given protocol version.
+    ///
+    /// Since version 4 binary packets are always messages, the opcode
+    /// of binary packets is lost in that case.
+    pub fn write_to_with<W: Write>(&self, writer: &mut W, protocol: Protocol) -> IoResult<()> {
+        write_packet!(self, writer, protocol)
+    }
+
+    /// Writes the packet as binary payload into the given `writer`.
+    pub fn write_payload_binary_to<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        let (type_byte, data) = match self.payload {
+            Payload::Binary(ref data) => {
+                let mut buf = Vec::with_capacity(data.len() + 1);
+                buf.push(self.opcode as u8);
+                buf.extend_from_slice(data);
+                (1u8, buf)
+            },
+            Payload::String(_) => (0u8, self.to_string().into_bytes())
+        };
+        let length_digits = data.len().to_string().bytes().map(|b| b - b'0').collect::<Vec<_>>();
+
+        try!(writer.write_all(&[type_byte]));
+        try!(writer.write_all(&length_digits));
+        try!(writer.write_all(&[LENGTH_SEPARATOR]));
+        writer.write_all(&data)
+    }
+
+    /// Writes the given packets as one payload in the encoding of the
+    /// given protocol version into the `writer`.
+    pub fn write_all_to<W: Write>(packets: &[Packet], writer: &mut W, protocol: Protocol) -> IoResult<()> {
+        for (index, packet) in packets.iter().enumerate() {
+            match protocol {
+                Protocol::V3 => try!(packet.write_payload_to(writer)),
+                Protocol::V4 => {
+                    if index > 0 {
+                        try!(write!(writer, "{}", RECORD_SEPARATOR));
+                    }
+                    try!(packet.write_to_with(writer, protocol));
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Writes the packet as payload into the given `writer`.
     pub fn write_payload_to<W: Write>(&self, writer: &mut W) -> IoResult<()> {
         // If we can precompute the length, we write the contents directly
@@ -207,8 +344,20 @@ impl FromStr for Packet {
 }
 
 impl From<Packet> for ws::Message {
+    /// Converts the packet into a websocket message.
+    ///
+    /// Binary packets are sent as binary frames prefixed with the
+    /// opcode byte instead of being base64-encoded.
     fn from(p: Packet) -> Self {
-        ws::Message::Text(p.to_string())
+        match p.payload {
+            Payload::Binary(data) => {
+                let mut buf = Vec::with_capacity(data.len() + 1);
+                buf.push(p.opcode as u8);
+                buf.extend_from_slice(&data);
+                ws::Message::Binary(buf)
+            },
+            Payload::String(_) => ws::Message::Text(p.to_string())
+        }
     }
 }
 
@@ -286,6 +435,32 @@ impl OpCode {
     }
 }
 
+/// The revision of the engine.io protocol spoken with the server.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum Protocol {
+    /// Protocol version 3, used by engine.io 1.x to 3.x.
+    V3,
+
+    /// Protocol version 4, used by engine.io 4.x and newer (socket.io 3+).
+    V4
+}
+
+impl Protocol {
+    /// Gets the value of the `EIO` query parameter for the version.
+    pub fn version(&self) -> u8 {
+        match *self {
+            Protocol::V3 => 3,
+            Protocol::V4 => 4
+        }
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::V3
+    }
+}
+
 /// The message's payload.
 #[derive(Clone, Debug, Eq, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Payload {
@@ -496,4 +671,79 @@ mod tests {
 
         assert_eq!(str, format!("12:4{}14:b4{}", STRING_PAYLOAD, BINARY_PAYLOAD_B64))
     }
+
+    #[test]
+    fn payload_binary_encoding_binary() {
+        let p = Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec());
+        let mut buf = Vec::new();
+        p.write_payload_binary_to(&mut buf).expect("Writing binary payload to buffer failed.");
+
+        let mut expected = vec![0x01, 1, 0, 0xFF, 4];
+        expected.extend_from_slice(&BINARY_PAYLOAD);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn payload_binary_encoding_string() {
+        let p = Packet::with_str(OpCode::Message, STRING_PAYLOAD);
+        let mut buf = Vec::new();
+        p.write_payload_binary_to(&mut buf).expect("Writing string payload to buffer failed.");
+
+        let mut expected = vec![0x00, 1, 2, 0xFF];
+        expected.extend_from_slice(format!("4{}", STRING_PAYLOAD).as_bytes());
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn payload_binary_multiple_decoding() {
+        use std::io::Cursor;
+
+        let p1 = Packet::with_str(OpCode::Message, STRING_PAYLOAD);
+        let p2 = Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec());
+        let mut buf = Cursor::new(Vec::new());
+        p1.write_payload_binary_to(&mut buf).expect("Failed to write string packet into buffer.");
+        p2.write_payload_binary_to(&mut buf).expect("Failed to write binary packet into buffer.");
+        buf.set_position(0);
+
+        let dec = Packet::from_reader_all_binary(&mut buf).expect("Failed to read multiple packets from buffer.");
+        assert!(dec.len() == 2, "Could not read all packets from buffer.");
+        assert_eq!(dec[0], p1);
+        assert_eq!(dec[1], p2);
+    }
+
+    #[test]
+    fn packet_binary_encoding_v4() {
+        let p = Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec());
+        let mut buf = Vec::new();
+        p.write_to_with(&mut buf, Protocol::V4).expect("Failed to write packet to buffer.");
+        assert_eq!(buf, format!("b{}", BINARY_PAYLOAD_B64).as_bytes());
+    }
+
+    #[test]
+    fn packet_binary_decoding_v4() {
+        let p = Packet::from_str_with(&format!("b{}", BINARY_PAYLOAD_B64), Protocol::V4).expect("Failed to parse v4 binary packet.");
+        assert_eq!(p, Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec()));
+    }
+
+    #[test]
+    fn payload_multiple_encoding_v4() {
+        let packets = vec![
+            Packet::with_str(OpCode::Message, STRING_PAYLOAD),
+            Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec())
+        ];
+        let mut buf = Vec::new();
+        Packet::write_all_to(&packets, &mut buf, Protocol::V4).expect("Failed to write v4 payload to buffer.");
+        assert_eq!(buf, format!("4{}\x1eb{}", STRING_PAYLOAD, BINARY_PAYLOAD_B64).as_bytes());
+    }
+
+    #[test]
+    fn payload_multiple_decoding_v4() {
+        let buf = format!("2\x1e4{}\x1eb{}", STRING_PAYLOAD, BINARY_PAYLOAD_B64);
+        let dec = Packet::from_reader_all_with(&mut buf.as_bytes(), Protocol::V4).expect("Failed to read v4 payload from buffer.");
+        assert_eq!(dec, vec![
+            Packet::with_str(OpCode::Ping, ""),
+            Packet::with_str(OpCode::Message, STRING_PAYLOAD),
+            Packet::with_binary(OpCode::Message, BINARY_PAYLOAD.to_vec())
+        ]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+//! Contains the reconnection policy.
+//!
+//! When a connection dies because of an error, it can be reestablished
+//! automatically. The attempts are spaced out with an exponential
+//! backoff, which is randomized a little so that a fleet of clients
+//! doesn't hammer a recovering server in lockstep.
+
+use std::time::Duration;
+use rand::random;
+
+/// Configures the automatic reconnection of a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// The delay before the first reconnection attempt.
+    ///
+    /// Default: 1 second.
+    pub initial_delay: Duration,
+
+    /// The factor by which the delay grows with every attempt.
+    ///
+    /// Default: 2.
+    pub multiplier: f64,
+
+    /// The upper bound of the delay between two attempts.
+    ///
+    /// Default: 5 seconds.
+    pub max_delay: Duration,
+
+    /// The number of attempts after which reconnecting is given up,
+    /// or `None` to try forever.
+    ///
+    /// Default: `None`.
+    pub max_attempts: Option<u32>,
+
+    /// The randomization factor in the range of `[0, 1]`. The delay
+    /// is varied randomly by up to this fraction in either direction.
+    ///
+    /// Default: 0.5.
+    pub jitter: f64
+}
+
+impl ReconnectPolicy {
+    /// Computes the randomized delay before the given (zero based) attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with(attempt, random::<f64>())
+    }
+
+    /// Computes the delay before the given (zero based) attempt from
+    /// a random value in the range of `[0, 1)`.
+    fn delay_with(&self, attempt: u32, rand: f64) -> Duration {
+        let base = to_millis(self.initial_delay) * self.multiplier.powi(attempt as i32);
+        let deviation = (rand * 2.0 - 1.0) * self.jitter * base;
+        let delay = (base + deviation).min(to_millis(self.max_delay)).max(0.0);
+        Duration::from_millis(delay as u64)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+            jitter: 0.5
+        }
+    }
+}
+
+fn to_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + (duration.subsec_nanos() / 1_000_000) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn delay_exponential() {
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            max_delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(1), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn delay_capped() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay_with(10, 0.5), Duration::from_secs(5));
+        assert_eq!(policy.delay_with(10, 0.99), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn delay_jitter() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay_with(1, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay_with(1, 0.5), Duration::from_secs(2));
+        assert_eq!(policy.delay_with(1, 0.75), Duration::from_secs(2) + Duration::from_millis(500));
+    }
+}
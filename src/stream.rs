@@ -0,0 +1,235 @@
+//! Contains the `futures` adapters of a client.
+//!
+//! Incoming events are delivered through an `EventStream`, outgoing
+//! packets are sent through a `PacketSink`. Only available with the
+//! `stream` feature.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use ::{Client, Connection, EngineError, EngineEvent, Packet};
+use eventual::{Async, AsyncError};
+use futures::{Async as Poll, AsyncSink, Sink, StartSend, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::task::{self, Task};
+
+const SINK_STATE_POISONED: &'static str = "Failed to lock packet sink state.";
+const SEND_ABORTED: &'static str = "Sending the packet was aborted.";
+
+/// A stream of the events of a client.
+///
+/// The stream keeps the client alive, the connection is closed once
+/// the stream is dropped. The stream ends once the connection has been
+/// closed or lost for good, i.e. after a `Disconnect`, a
+/// `ReconnectFailed` or an `Error` that isn't followed by an automatic
+/// reconnection.
+pub struct EventStream {
+    client: Client,
+    rx: UnboundedReceiver<EngineEvent>
+}
+
+impl EventStream {
+    /// Creates a new stream of the events received through the
+    /// given channel.
+    pub fn new(client: Client, rx: UnboundedReceiver<EngineEvent>) -> EventStream {
+        EventStream {
+            client: client,
+            rx: rx
+        }
+    }
+
+    /// Gets the client whose events are streamed.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl Debug for EventStream {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "EventStream {{ client: {:?}, ... }}", self.client)
+    }
+}
+
+impl Stream for EventStream {
+    type Item = EngineEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Result<Poll<Option<EngineEvent>>, ()> {
+        self.rx.poll()
+    }
+}
+
+/// A sink sending packets through a connection.
+///
+/// The sink accepts packets as long as they fit into the buffer limits
+/// of the connection (see `BufferLimits`), and applies backpressure
+/// otherwise. The first failed send fails the sink for good, including
+/// its clones.
+#[derive(Clone)]
+pub struct PacketSink {
+    connection: Connection,
+    state: Arc<Mutex<SinkState>>
+}
+
+struct SinkState {
+    error: Option<Arc<EngineError>>,
+    in_flight: usize,
+    tasks: Vec<Task>
+}
+
+impl SinkState {
+    fn check(&self) -> Result<(), EngineError> {
+        match self.error {
+            Some(ref err) => Err(EngineError::Shared(err.clone())),
+            None => Ok(())
+        }
+    }
+}
+
+impl PacketSink {
+    /// Creates a new sink sending through the given connection.
+    pub fn new(connection: Connection) -> PacketSink {
+        PacketSink {
+            connection: connection,
+            state: Arc::new(Mutex::new(SinkState {
+                error: None,
+                in_flight: 0,
+                tasks: Vec::new()
+            }))
+        }
+    }
+
+    /// Gets the number of packets that haven't been sent yet.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().expect(SINK_STATE_POISONED).in_flight
+    }
+}
+
+impl Debug for PacketSink {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "PacketSink {{ connection: {:?}, in_flight: {} }}", self.connection, self.in_flight())
+    }
+}
+
+impl Sink for PacketSink {
+    type SinkItem = Packet;
+    type SinkError = EngineError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, EngineError> {
+        try!(self.state.lock().expect(SINK_STATE_POISONED).check());
+
+        let packets = vec![packet];
+        if self.connection.exceeds_buffer_limits(&packets) {
+            let task = task::park();
+            self.connection.notify_room(move || task.unpark());
+            return Ok(AsyncSink::NotReady(packets.into_iter().next().unwrap()));
+        }
+        self.state.lock().expect(SINK_STATE_POISONED).in_flight += 1;
+
+        let state = self.state.clone();
+        self.connection.send_all(packets).receive(move |res| {
+            let tasks = {
+                let mut state = state.lock().expect(SINK_STATE_POISONED);
+                state.in_flight -= 1;
+                let err = match res {
+                    Ok(_) => None,
+                    Err(AsyncError::Failed(EngineError::Shared(err))) => Some(err),
+                    Err(AsyncError::Failed(err)) => Some(Arc::new(err)),
+                    Err(AsyncError::Aborted) => Some(Arc::new(EngineError::invalid_state(SEND_ABORTED)))
+                };
+                if state.error.is_none() {
+                    state.error = err;
+                }
+                mem::replace(&mut state.tasks, Vec::new())
+            };
+            for task in tasks {
+                task.unpark();
+            }
+        });
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Result<Poll<()>, EngineError> {
+        let mut state = self.state.lock().expect(SINK_STATE_POISONED);
+        try!(state.check());
+        if state.in_flight == 0 {
+            Ok(Poll::Ready(()))
+        } else {
+            state.tasks.push(task::park());
+            Ok(Poll::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PacketSink;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use ::{BufferLimits, ClientBuilder, Connection, EngineEvent, OpCode, Packet};
+    use eventual::Async;
+    use futures::{Async as Poll, AsyncSink, Future, Sink, Stream};
+    use futures::future;
+    use testing::serve;
+
+    /// Runs the given function within a task, so that it may park it.
+    fn in_task<T, F: FnOnce() -> T>(f: F) -> T {
+        future::lazy(|| Ok::<T, ()>(f())).wait().unwrap()
+    }
+
+    #[test]
+    fn backpressure() {
+        let connection = Connection::new();
+        connection.set_buffer_limits(BufferLimits { max_packets: Some(1), ..Default::default() });
+        connection.set_request_timeout(Duration::from_secs(1));
+        connection.connect(serve(Arc::new(Mutex::new(Ok(Vec::new()))), false), Box::new(|_| {})).await().unwrap();
+
+        let mut sink = PacketSink::new(connection.clone());
+        in_task(|| {
+            match sink.start_send(Packet::with_str(OpCode::Message, "Hello")) {
+                Ok(AsyncSink::Ready) => {},
+                res => panic!("Expected the packet to be accepted, got {:?}.", res)
+            }
+            // The first packet is still pending, so the second one would
+            // exceed the limit.
+            match sink.start_send(Packet::with_str(OpCode::Message, "World")) {
+                Ok(AsyncSink::NotReady(_)) => {},
+                res => panic!("Expected backpressure, got {:?}.", res)
+            }
+            match sink.poll_complete() {
+                Ok(Poll::NotReady) => {},
+                res => panic!("Expected the sink to be busy, got {:?}.", res)
+            }
+        });
+        assert_eq!(sink.in_flight(), 1);
+        connection.disconnect().await().unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        // Sends through a connection that isn't connected fail right away.
+        let mut sink = PacketSink::new(Connection::new());
+        let mut clone = sink.clone();
+        in_task(|| {
+            assert!(sink.start_send(Packet::with_str(OpCode::Message, "Hello")).is_ok());
+            assert!(sink.poll_complete().is_err());
+            // The error sticks, also for the clones of the sink.
+            assert!(sink.poll_complete().is_err());
+            assert!(clone.start_send(Packet::with_str(OpCode::Message, "Hello")).is_err());
+        });
+    }
+
+    #[test]
+    fn termination() {
+        let client = ClientBuilder::new().build().unwrap();
+        client.connect(&serve(Arc::new(Mutex::new(Ok(Vec::new()))), true)).await().unwrap();
+        let stream = client.into_stream();
+        stream.client().disconnect().await().unwrap();
+
+        let events: Vec<EngineEvent> = stream.wait().map(|ev| ev.unwrap()).collect();
+        match events.last() {
+            Some(&EngineEvent::Disconnect) => {},
+            ev => panic!("Expected the stream to end with a disconnect, got {:?}.", ev)
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Contains the fixtures shared by the tests.
+
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use packet::{OpCode, Packet, Payload, Protocol};
+use url::Url;
+
+/// The messages posted to a test server, or the error it ran into.
+pub type Posted = Arc<Mutex<Result<Vec<String>, String>>>;
+
+/// Serves the requests of a polling transport on a local port,
+/// answering polls with a noop and collecting the messages posted.
+///
+/// Overlapping POST requests are recorded as an error. Unless
+/// `answers_posts` is set, POST requests are left hanging, so that the
+/// sends stay pending.
+pub fn serve(posted: Posted, answers_posts: bool) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/engine.io/", listener.local_addr().unwrap())).unwrap();
+    let posting = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (posted, posting) = (posted.clone(), posting.clone());
+            thread::spawn(move || {
+                let mut stream = BufReader::new(stream.unwrap());
+                loop {
+                    let mut head = Vec::new();
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).unwrap_or(0) > 2 {
+                        head.push(line.trim().to_lowercase());
+                        line.clear();
+                    }
+                    if head.is_empty() {
+                        return;
+                    }
+                    let length = head.iter()
+                        .filter_map(|header| header.split("content-length:").nth(1))
+                        .filter_map(|length| length.trim().parse().ok())
+                        .next()
+                        .unwrap_or(0);
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).unwrap();
+
+                    let response = if head[0].starts_with("post") {
+                        if !answers_posts {
+                            // Keep the request hanging until the test is over.
+                            thread::sleep(Duration::from_secs(60));
+                            return;
+                        }
+                        if posting.fetch_add(1, Ordering::SeqCst) > 0 {
+                            *posted.lock().unwrap() = Err("Received overlapping POST requests.".to_owned());
+                        }
+                        thread::sleep(Duration::from_millis(20));
+                        let packets = Packet::from_reader_all_with(&mut Cursor::new(body), Protocol::V3).unwrap();
+                        if let Ok(ref mut posted) = *posted.lock().unwrap() {
+                            posted.extend(packets.iter().filter(|packet| packet.opcode() == OpCode::Message).map(|packet| match *packet.payload() {
+                                Payload::String(ref string) => string.clone(),
+                                Payload::Binary(_) => unreachable!()
+                            }));
+                        }
+                        posting.fetch_sub(1, Ordering::SeqCst);
+                        "ok".to_owned()
+                    } else {
+                        let packet = if head[0].contains("sid=") {
+                            thread::sleep(Duration::from_millis(100));
+                            Packet::with_str(OpCode::Noop, "")
+                        } else {
+                            Packet::with_str(OpCode::Open, r#"{"sid":"test","upgrades":[],"pingInterval":25000,"pingTimeout":60000}"#)
+                        };
+                        let mut buf = Vec::new();
+                        Packet::write_all_to(&[packet], &mut buf, Protocol::V3).unwrap();
+                        String::from_utf8(buf).unwrap()
+                    };
+                    let _ = write!(stream.get_mut(), "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=UTF-8\r\nContent-Length: {}\r\n\r\n{}", response.len(), response);
+                }
+            });
+        }
+    });
+    url
+}
@@ -0,0 +1,121 @@
+//! Contains the TLS configuration.
+//!
+//! The configuration applies to the HTTPS requests of the polling
+//! transport as well as to secure websockets. Only available with
+//! the `ssl` feature.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use ::EngineError;
+use hyper::net::{HttpStream, SslClient};
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::ssl::error::SslError;
+use openssl::x509::X509FileType;
+use openssl_verify::verify_callback;
+
+/// Configures the TLS connections of a client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    accept_invalid_certs: bool,
+    client_certificate: Option<(PathBuf, PathBuf)>,
+    root_certificates: Vec<PathBuf>,
+    sni_hostname: Option<String>
+}
+
+impl TlsConfig {
+    /// Creates a new TLS configuration trusting the system's root
+    /// certificates.
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Additionally trusts the root certificates in the given PEM file.
+    pub fn add_root_certificate<P: AsRef<Path>>(mut self, pem_file: P) -> TlsConfig {
+        self.root_certificates.push(pem_file.as_ref().to_owned());
+        self
+    }
+
+    /// Authenticates with the client certificate and private key in the
+    /// given PEM files (mutual TLS).
+    pub fn client_certificate<C: AsRef<Path>, K: AsRef<Path>>(mut self, cert_file: C, key_file: K) -> TlsConfig {
+        self.client_certificate = Some((cert_file.as_ref().to_owned(), key_file.as_ref().to_owned()));
+        self
+    }
+
+    /// Sets whether invalid certificates are accepted.
+    ///
+    /// This makes the connection vulnerable to man-in-the-middle attacks
+    /// and must only be used for testing, e.g. against staging servers
+    /// with self-signed certificates.
+    pub fn danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> TlsConfig {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Sets the host name sent through SNI and verified against the
+    /// server's certificate, instead of the one from the URL.
+    pub fn sni_hostname<S: Into<String>>(mut self, hostname: S) -> TlsConfig {
+        self.sni_hostname = Some(hostname.into());
+        self
+    }
+
+    /// Creates the SSL context holding the certificates.
+    pub fn context(&self) -> Result<SslContext, SslError> {
+        let mut ctx = try!(SslContext::new(SslMethod::Sslv23));
+        try!(ctx.set_default_verify_paths());
+        for pem_file in &self.root_certificates {
+            try!(ctx.set_CA_file(pem_file));
+        }
+        if let Some((ref cert_file, ref key_file)) = self.client_certificate {
+            try!(ctx.set_certificate_file(cert_file, X509FileType::PEM));
+            try!(ctx.set_private_key_file(key_file, X509FileType::PEM));
+            try!(ctx.check_private_key());
+        }
+        Ok(ctx)
+    }
+
+    /// Creates an SSL session for a connection to the given host.
+    pub fn ssl(&self, ctx: &SslContext, host: &str) -> Result<Ssl, SslError> {
+        let mut ssl = try!(Ssl::new(ctx));
+        let hostname = self.sni_hostname.clone().unwrap_or_else(|| host.to_owned());
+        try!(ssl.set_hostname(&hostname));
+        if self.accept_invalid_certs {
+            ssl.set_verify_callback(SSL_VERIFY_NONE, |_, _| true);
+        } else {
+            ssl.set_verify_callback(SSL_VERIFY_PEER, move |preverify_ok, x509_ctx| {
+                verify_callback(&hostname, preverify_ok, x509_ctx)
+            });
+        }
+        Ok(ssl)
+    }
+}
+
+/// Wraps the HTTPS connections of the HTTP client according to a
+/// TLS configuration.
+pub struct TlsClient(Arc<SslContext>, TlsConfig);
+
+impl TlsClient {
+    /// Creates a new TLS client from the given configuration.
+    pub fn new(cfg: &TlsConfig) -> Result<TlsClient, EngineError> {
+        match cfg.context() {
+            Ok(ctx) => Ok(TlsClient(Arc::new(ctx), cfg.clone())),
+            Err(err) => Err(EngineError::invalid_config(err))
+        }
+    }
+}
+
+impl Debug for TlsClient {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "TlsClient(..., {:?})", self.1)
+    }
+}
+
+impl SslClient for TlsClient {
+    type Stream = SslStream<HttpStream>;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> ::hyper::Result<Self::Stream> {
+        let ssl = try!(self.1.ssl(&self.0, host));
+        SslStream::connect(ssl, stream).map_err(From::from)
+    }
+}
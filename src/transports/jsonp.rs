@@ -0,0 +1,166 @@
+//! The JSONP polling transport.
+//!
+//! Some legacy deployments and proxies only let the JSONP flavor of
+//! long polling through. It works just like XHR polling, except that
+//! responses are wrapped in a `___eio[n]("...");` script and packets
+//! are sent as form-encoded `d=` POST bodies.
+
+use super::{check_status, request_headers, Config, Options, Pending, Polling, Transport};
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::time::Duration;
+use ::{EngineError, EngineEvent};
+use eventual::{Async, Future};
+use hyper::Client;
+use hyper::client::Response;
+use hyper::header::ContentType;
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use packet::{Packet, Protocol};
+use rustc_serialize::json::decode;
+use url::Url;
+use url::form_urlencoded::Serializer;
+
+/// The index of the `___eio` callback. Since there is only one
+/// request in flight per direction, the index is fixed.
+const JSONP_INDEX: &'static str = "0";
+const RESPONSE_INVALID: &'static str = "The JSONP response was not wrapped in a ___eio[n](...) call.";
+
+/// The JSONP long polling transport.
+#[derive(Debug)]
+pub struct Jsonp(Polling);
+
+impl Jsonp {
+    /// Creates a new instance of a JSONP polling transport and automatically
+    /// connects to the given endpoint.
+    ///
+    /// ## Parameters
+    /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
+    ///   of the server to connect to.
+    /// - `opts: Options`: The options of the transport.
+    /// - `callback: C`: Callback to call when asynchronous events are ready.
+    pub fn new<C: FnMut(EngineEvent) + Send + 'static>(url: Url, mut opts: Options, callback: C) -> Future<Jsonp, EngineError> {
+        opts.force_jsonp = true;
+        Polling::new(url, opts, callback).map(Jsonp)
+    }
+
+    /// Creates a new instance of a JSONP polling transport from a given
+    /// configuration (i.e. a reconnection). This does not fire the
+    /// `connect`-callback.
+    ///
+    /// ## Parameters
+    /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
+    ///   of the server to connect to.
+    /// - `opts: Options`: The options of the transport.
+    /// - `callback: C`: Callback to call when asynchronous events are ready.
+    /// - `cfg: Config`: A transport configuration used to recreate the
+    ///   transport after it has been interrupted by network issues.
+    pub fn with_cfg<C: FnMut(EngineEvent) + Send + 'static>(url: Url, mut opts: Options, callback: C, cfg: Config) -> Jsonp {
+        opts.force_jsonp = true;
+        Jsonp(Polling::with_cfg(url, opts, callback, cfg))
+    }
+
+    /// Gets the configuration associated with the transport.
+    pub fn cfg(&self) -> &Config {
+        self.0.cfg()
+    }
+}
+
+impl Transport for Jsonp {
+    fn close(&self) -> Future<(), EngineError> {
+        self.0.close()
+    }
+
+    fn close_gracefully(&self, timeout: Duration) -> Future<(), EngineError> {
+        self.0.close_gracefully(timeout)
+    }
+
+    fn pause(&self) -> Future<(), EngineError> {
+        self.0.pause()
+    }
+
+    fn release(&self) -> Future<Pending, EngineError> {
+        self.0.release()
+    }
+
+    fn send(&self, msgs: Vec<Packet>) -> Future<(), EngineError> {
+        self.0.send(msgs)
+    }
+
+    fn start(&self) -> Future<(), EngineError> {
+        self.0.start()
+    }
+}
+
+/// Adds the `j` parameter marking a request as JSONP request.
+pub fn append_index(url: &mut Url) {
+    url.query_pairs_mut().append_pair("j", JSONP_INDEX);
+}
+
+/// Reads the packets out of a JSONP poll response.
+pub fn decode_response(mut response: Response, protocol: Protocol) -> Result<Vec<Packet>, EngineError> {
+    let mut body = String::new();
+    try!(response.read_to_string(&mut body));
+    unwrap_script(&body).and_then(|payload| Packet::from_reader_all_with(&mut payload.as_bytes(), protocol))
+}
+
+/// Sends the given packets as form-encoded JSONP POST body.
+pub fn send(client: &Client, url: Url, opts: &Options, packets: &[Packet]) -> Result<(), EngineError> {
+    let mut payload = Vec::new();
+    try!(Packet::write_all_to(packets, &mut payload, opts.protocol));
+    let payload = try!(String::from_utf8(payload).map_err(|_| EngineError::Utf8));
+    let body = encode_body(&payload);
+
+    let content_type = ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![]));
+    let headers = request_headers(opts, &url);
+    match client.post(url).headers(headers).header(content_type).body(&body[..]).send() {
+        Ok(response) => {
+            opts.cookies.store_from(&response.headers);
+            check_status(response).map(|_| ())
+        },
+        Err(err) => Err(err.into())
+    }
+}
+
+/// Encodes a payload as `d=` form body.
+///
+/// Newlines are escaped like the JS client does, because the server
+/// unescapes them again.
+fn encode_body(payload: &str) -> String {
+    let escaped = payload.replace("\\n", "\\\n").replace("\n", "\\n");
+    Serializer::new(String::new()).append_pair("d", &escaped).finish()
+}
+
+/// Extracts the payload string from a `___eio[n]("...");` script.
+fn unwrap_script(body: &str) -> Result<String, EngineError> {
+    match (body.find('('), body.rfind(')')) {
+        (Some(start), Some(end)) if start < end => decode(&body[start + 1..end]).map_err(|err| err.into()),
+        _ => Err(EngineError::Io(IoError::new(ErrorKind::InvalidData, RESPONSE_INVALID)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_body, unwrap_script};
+
+    #[test]
+    fn unwrap() {
+        let payload = unwrap_script("___eio[0](\"12:4Hello World\");").expect("Failed to unwrap JSONP script.");
+        assert_eq!(payload, "12:4Hello World");
+    }
+
+    #[test]
+    fn unwrap_escaped() {
+        let payload = unwrap_script("___eio[3](\"7:4a\\\"b\\nc\");").expect("Failed to unwrap JSONP script.");
+        assert_eq!(payload, "7:4a\"b\nc");
+    }
+
+    #[test]
+    #[should_panic]
+    fn unwrap_invalid() {
+        unwrap_script("12:4Hello World").expect("Unwrapping a plain payload yielded an error. Test succeeded.");
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_body("6:4a b\n"), "d=6%3A4a+b%5Cn");
+    }
+}
@@ -1,19 +1,136 @@
 #![allow(dead_code)]
 
+mod jsonp;
 mod polling;
-//mod websocket;
+mod websocket;
 
 use std::cell::RefCell;
 use std::fmt::Debug;
+use std::sync::mpsc::channel;
+use std::thread;
 use std::time::Duration;
-use ::EngineError;
-use eventual::Future;
-use packet::Packet;
+use ::{BufferLimits, CookieJar, EngineError, OverflowPolicy, ProxyConfig};
+#[cfg(feature = "ssl")]
+use ::TlsConfig;
+use eventual::{Async, AsyncError, Complete, Future};
+use hyper::client::Response;
+use hyper::header::Headers;
+use outgoing::Amount;
+use packet::{OpCode, Packet, Protocol};
 use rand::{Rng, weak_rng, XorShiftRng};
 use url::Url;
 
+pub use self::jsonp::Jsonp;
 pub use self::polling::Polling;
-//pub use self::websocket::*;
+pub use self::websocket::Socket;
+
+/// Packets that have been handed to a transport but could not be
+/// sent yet, together with the handles that complete the futures
+/// returned from `Transport::send`.
+pub type Pending = Vec<(Vec<Packet>, Complete<(), EngineError>)>;
+
+/// The query parameters set by the transports themselves.
+pub const RESERVED_PARAMETERS: &'static [&'static str] = &["b64", "EIO", "j", "sid", "transport"];
+
+/// Options shared by all transports of a connection.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The limits of the buffer of packets waiting to be sent.
+    pub buffer_limits: BufferLimits,
+
+    /// The cookies sent with every request. The jar is filled from the
+    /// responses of the server.
+    pub cookies: CookieJar,
+
+    /// Whether binary data is always sent base64-encoded (`b64=1`),
+    /// even if the binary payload encoding could be used.
+    pub force_base64: bool,
+
+    /// Whether long polling is done through JSONP instead of XHR.
+    ///
+    /// JSONP always transfers binary data base64-encoded.
+    pub force_jsonp: bool,
+
+    /// How long the handshake may take before it is given up.
+    pub handshake_timeout: Duration,
+
+    /// Additional HTTP headers sent with every request, including the
+    /// websocket handshake.
+    pub headers: Headers,
+
+    /// How long the polling transport gathers packets before sending
+    /// them in a single request.
+    pub linger: Duration,
+
+    /// The maximum size of the packet payloads batched into a single
+    /// polling request in bytes, or `None` for no limit.
+    pub max_payload_size: Option<usize>,
+
+    /// The protocol version to speak.
+    pub protocol: Protocol,
+
+    /// How the server is reached.
+    pub proxy: ProxyConfig,
+
+    /// Additional query parameters sent with every request.
+    pub query: Vec<(String, String)>,
+
+    /// The read and write timeout of the HTTP requests.
+    pub request_timeout: Duration,
+
+    /// The name of the cache-busting timestamp query parameter.
+    pub timestamp_param: String,
+
+    /// The configuration of TLS connections.
+    #[cfg(feature = "ssl")]
+    pub tls: TlsConfig,
+
+    /// The number of threads doing the HTTP requests of a polling
+    /// transport. With a single thread, sends wait for the running poll.
+    pub worker_threads: usize
+}
+
+impl Options {
+    /// Checks whether the binary payload encoding is used for polling.
+    ///
+    /// Only protocol version 3 knows about binary payloads.
+    pub fn binary_payloads(&self) -> bool {
+        !self.force_base64 && !self.force_jsonp && self.protocol == Protocol::V3
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            buffer_limits: BufferLimits::default(),
+            cookies: CookieJar::new(),
+            force_base64: false,
+            force_jsonp: false,
+            handshake_timeout: Duration::from_secs(5),
+            headers: Headers::new(),
+            linger: Duration::from_millis(0),
+            max_payload_size: None,
+            protocol: Protocol::default(),
+            proxy: ProxyConfig::default(),
+            query: Vec::new(),
+            request_timeout: Duration::from_secs(5),
+            timestamp_param: "t".to_owned(),
+            #[cfg(feature = "ssl")]
+            tls: TlsConfig::default(),
+            worker_threads: 2
+        }
+    }
+}
+
+/// The kinds of transports a connection can use.
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+pub enum TransportType {
+    /// HTTP long polling (XHR or JSONP).
+    Polling,
+
+    /// Websockets.
+    WebSocket
+}
 
 thread_local!(static RNG: RefCell<XorShiftRng> = RefCell::new(weak_rng()));
 
@@ -25,15 +142,37 @@ thread_local!(static RNG: RefCell<XorShiftRng> = RefCell::new(weak_rng()));
 /// web sockets. Since using web sockets is not always possible,
 /// the upgrade to will only be done if both parties can really
 /// communicate over the socket.
-pub trait Transport : Debug {
+pub trait Transport : Debug + Send {
     /// Asynchronously closes the transport.
+    ///
+    /// Packets that haven't been sent yet are dropped.
     fn close(&self) -> Future<(), EngineError>;
 
+    /// Asynchronously closes the transport after the packets that haven't
+    /// been sent yet have been flushed, waiting at most for the given
+    /// timeout.
+    ///
+    /// Transports that can't flush their packets close right away.
+    fn close_gracefully(&self, _timeout: Duration) -> Future<(), EngineError> {
+        self.close()
+    }
+
     /// Pauses the transport so that the buffers are flushed and
     /// no more messages are sent.
     fn pause(&self) -> Future<(), EngineError>;
 
+    /// Shuts down the transport without closing the engine.io session
+    /// and hands out the packets that haven't been sent yet.
+    ///
+    /// This is used when the session is upgraded to another transport
+    /// or resumed after the transport has been interrupted. No events
+    /// are fired through the transport's callback afterwards.
+    fn release(&self) -> Future<Pending, EngineError>;
+
     /// Sends a list of messages through the transport.
+    ///
+    /// If the transport has been interrupted by network issues, the
+    /// messages are kept until the transport is released.
     fn send(&self, Vec<Packet>) -> Future<(), EngineError>;
 
     /// Restarts the transport when it has been paused.
@@ -67,13 +206,132 @@ impl Config {
     }
 }
 
-fn append_eio_parameters(url: &mut Url, sid: Option<&str>) {
+fn append_eio_parameters(url: &mut Url, opts: &Options, transport: &str, sid: Option<&str>) {
     let mut query = url.query_pairs_mut();
-    query.append_pair("EIO", "3")
-         .append_pair("transport", "polling")
-         .append_pair("t", &RNG.with(|rc| rc.borrow_mut().gen_ascii_chars().take(7).collect::<String>()))
-         .append_pair("b64", "1");
+    for &(ref key, ref value) in &opts.query {
+        query.append_pair(key, value);
+    }
+    query.append_pair("EIO", &opts.protocol.version().to_string())
+         .append_pair("transport", transport)
+         .append_pair(&opts.timestamp_param, &RNG.with(|rc| rc.borrow_mut().gen_ascii_chars().take(7).collect::<String>()));
+    if !opts.binary_payloads() {
+        query.append_pair("b64", "1");
+    }
     if let Some(id) = sid {
         query.append_pair("sid", id);
     }
-}
\ No newline at end of file
+}
+
+/// Drops the oldest packets of the buffer until it fits the limits
+/// again, if the limits are enforced that way.
+///
+/// The newest send is kept even if it exceeds the limits on its own.
+/// Heartbeat packets are neither counted nor dropped, since the
+/// session is closed without them.
+///
+/// ## Returns
+/// The number of sends that have been dropped.
+pub fn evict_oldest(buffer: &mut Pending, limits: &BufferLimits) -> usize {
+    if limits.policy != OverflowPolicy::DropOldest {
+        return 0;
+    }
+    let (mut packets, mut bytes) = buffer.iter()
+        .filter(|&&(ref packets, _)| !is_heartbeat(packets))
+        .map(|&(ref packets, _)| Amount::of(packets))
+        .fold((0, 0), |(packets, bytes), amount| (packets + amount.packets, bytes + amount.bytes));
+    let mut count = 0;
+    while limits.is_exceeded_by(packets, bytes) {
+        let newest = buffer.len().saturating_sub(1);
+        let index = match buffer[..newest].iter().position(|&(ref packets, _)| !is_heartbeat(packets)) {
+            Some(index) => index,
+            None => break
+        };
+        let (dropped, tx) = buffer.remove(index);
+        let amount = Amount::of(&dropped);
+        packets -= amount.packets;
+        bytes -= amount.bytes;
+
+        tx.fail(EngineError::Dropped(dropped));
+        count += 1;
+    }
+    count
+}
+
+/// Adds a send to the buffer.
+///
+/// Heartbeat packets go out before the packets waiting to be sent, but
+/// after the heartbeat packets queued before them.
+pub fn enqueue(buffer: &mut Pending, packets: Vec<Packet>, tx: Complete<(), EngineError>) {
+    if is_heartbeat(&packets) {
+        let index = buffer.iter().take_while(|&&(ref packets, _)| is_heartbeat(packets)).count();
+        buffer.insert(index, (packets, tx));
+    } else {
+        buffer.push((packets, tx));
+    }
+}
+
+/// Checks whether the given send consists of pings and pongs.
+fn is_heartbeat(packets: &[Packet]) -> bool {
+    !packets.is_empty() && packets.iter().all(|packet| match packet.opcode() {
+        OpCode::Ping | OpCode::Pong => true,
+        _ => false
+    })
+}
+
+/// Fails the futures of packets that won't be sent anymore with
+/// `EngineError::Dropped`.
+pub fn drop_pending(pending: Pending) {
+    for (packets, tx) in pending {
+        tx.fail(EngineError::Dropped(packets));
+    }
+}
+
+/// Fails the given future with `EngineError::Timeout` unless it has
+/// been resolved within the given timeout. `on_timeout` is called to
+/// abandon the operation once the deadline has passed.
+pub fn with_deadline<T, F>(f: Future<T, EngineError>, timeout: Duration, on_timeout: F) -> Future<T, EngineError>
+    where T: Send + 'static,
+          F: FnOnce() + Send + 'static {
+    let (tx, deadline_f) = Future::pair();
+    let (res_tx, res_rx) = channel();
+    f.receive(move |res| {
+        let _ = res_tx.send(res);
+    });
+    thread::spawn(move || {
+        match res_rx.recv_timeout(timeout) {
+            Ok(Ok(val)) => tx.complete(val),
+            Ok(Err(AsyncError::Failed(err))) => tx.fail(err),
+            Ok(Err(AsyncError::Aborted)) => tx.abort(),
+            Err(_) => {
+                on_timeout();
+                tx.fail(EngineError::Timeout);
+            }
+        }
+    });
+    deadline_f
+}
+
+/// Gets the headers to send with a request to the given URL, i.e. the
+/// custom headers, the cookies and the proxy credentials.
+fn request_headers(opts: &Options, url: &Url) -> Headers {
+    let mut headers = opts.headers.clone();
+    opts.cookies.add_to(&mut headers);
+
+    // Only requests in absolute form are read by the proxy, tunneled
+    // ones authenticate through the `CONNECT` request.
+    if url.scheme() == "http" {
+        if let Some(auth) = opts.proxy.resolve(url).and_then(|proxy| proxy.authorization()) {
+            headers.set_raw("Proxy-Authorization", vec![auth.into_bytes()]);
+        }
+    }
+    headers
+}
+
+/// Turns a response with an unsuccessful status code into an error.
+fn check_status(response: Response) -> Result<Response, EngineError> {
+    if response.status.is_success() {
+        Ok(response)
+    } else {
+        Err(EngineError::Status(response.status))
+    }
+}
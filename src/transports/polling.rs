@@ -8,38 +8,48 @@
 //! is done only after it has been verified that websockets can
 //! indeed be used.
 
-use super::{append_eio_parameters, Config, Transport};
+use super::{append_eio_parameters, check_status, drop_pending, enqueue, evict_oldest, request_headers, with_deadline, Config, Options, Pending, Transport};
+use super::jsonp;
+use std::cmp;
 use std::io::{BufReader, Cursor, Error as IoError, ErrorKind, Write};
+use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender, SendError};
 use std::thread;
 use std::time::{Duration, Instant};
 use ::{EngineEvent, EngineError};
 use eventual::{Async, AsyncError, Complete, Future};
 use hyper::{Client, Error as HttpError};
+use hyper::header::ContentType;
+use hyper::mime::{Mime, SubLevel, TopLevel};
+use outgoing::{mark_transport_thread, Amount};
 use packet::{OpCode, Packet, Payload};
 use rustc_serialize::json::decode;
 use threadpool::ThreadPool;
 use url::Url;
 
 const EVENT_CHANNEL_DISCONNECTED: &'static str = "Event channel was disconnected. This means the connection has been shut down or an error occured.";
+const POLL_FAILED_WHILE_PAUSING: &'static str = "The poll request failed while the transport was being paused.";
 
-lazy_static! {
-    static ref HTTP_CLIENT: Client = {
-        let mut c = Client::new();
-        c.set_read_timeout(Some(Duration::from_secs(5)));
-        c.set_write_timeout(Some(Duration::from_secs(5)));
-        c
-    };
-}
-
-pub fn connect_async(url: Url) -> Future<Config, EngineError> {
+pub fn connect_async(url: Url, opts: Options) -> Future<Config, EngineError> {
     let tp = ThreadPool::new(1);
-    poll_async(&tp, url, Duration::from_secs(5), None).and_then(|packets| {
+    let timeout = opts.handshake_timeout;
+    let client = match http_client(&opts, &url) {
+        Ok(mut client) => {
+            // A single request must not outlast the whole handshake.
+            let request_timeout = cmp::min(opts.request_timeout, timeout);
+            client.set_read_timeout(Some(request_timeout));
+            client.set_write_timeout(Some(request_timeout));
+            Arc::new(client)
+        },
+        Err(err) => return Future::error(err)
+    };
+    let handshake_f = poll_async(&tp, client, url, opts, timeout, None).and_then(|packets| {
         match *packets[0].payload() {
             Payload::String(ref str) => decode(str).map_err(|err| err.into()),
             Payload::Binary(_) => Err(EngineError::Io(IoError::new(ErrorKind::InvalidData, "Received binary packet when string packet was expected in session initialization.")))
         }
-    })
+    });
+    with_deadline(handshake_f, timeout, || {})
 }
 
 /// The long polling transport.
@@ -53,9 +63,10 @@ impl Polling {
     /// ## Parameters
     /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
     ///   of the server to connect to.
+    /// - `opts: Options`: The options of the transport.
     /// - `callback: C`: Callback to call when asynchronous events are ready.
-    pub fn new<C: FnMut(EngineEvent) + Send + 'static>(url: Url, callback: C) -> Future<Polling, EngineError> {
-        connect_async(url.clone()).map(move |cfg| Polling::create(url, callback, cfg, false))
+    pub fn new<C: FnMut(EngineEvent) + Send + 'static>(url: Url, opts: Options, callback: C) -> Future<Polling, EngineError> {
+        connect_async(url.clone(), opts.clone()).map(move |cfg| Polling::create(url, opts, callback, cfg, false))
     }
 
     /// Creates a new instance of a long polling transport from a given
@@ -65,17 +76,21 @@ impl Polling {
     /// ## Parameters
     /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
     ///   of the server to connect to.
+    /// - `opts: Options`: The options of the transport.
     /// - `callback: C`: Callback to call when asynchronous events are ready.
     /// - `cfg: Config`: A transport configuration used to recreate the
     ///   transport after it has been interrupted by network issues.
-    pub fn with_cfg<C: FnMut(EngineEvent) + Send + 'static>(url: Url, callback: C, cfg: Config) -> Polling {
-        Polling::create(url, callback, cfg, true)
+    pub fn with_cfg<C: FnMut(EngineEvent) + Send + 'static>(url: Url, opts: Options, callback: C, cfg: Config) -> Polling {
+        Polling::create(url, opts, callback, cfg, true)
     }
 
-    fn create<C: FnMut(EngineEvent) + Send + 'static>(url: Url, callback: C, cfg: Config, previously_connected: bool) -> Polling {
+    fn create<C: FnMut(EngineEvent) + Send + 'static>(url: Url, opts: Options, callback: C, cfg: Config, previously_connected: bool) -> Polling {
         let (ev_tx, ev_rx) = channel();
         let cfg2 = cfg.clone();
-        thread::spawn(move || handle_polling(url, callback, cfg2, ev_rx, previously_connected));
+        thread::spawn(move || {
+            mark_transport_thread();
+            handle_polling(url, opts, callback, cfg2, ev_rx, previously_connected)
+        });
         Polling(ev_tx, cfg)
     }
 
@@ -94,7 +109,7 @@ impl Drop for Polling {
 impl Transport for Polling {
     fn close(&self) -> Future<(), EngineError> {
         let (tx, f) = Future::pair();
-        if let Err(SendError(PollEvent::Close(tx))) = self.0.send(PollEvent::Close(tx)) {
+        if let Err(SendError(PollEvent::Close(tx, _))) = self.0.send(PollEvent::Close(tx, None)) {
             // Never mind if we fail to transmit the poll event here.
             // In case the channel is disconnected, the background thread
             // has hung up anyway and we're not connected anymore.
@@ -103,6 +118,14 @@ impl Transport for Polling {
         f
     }
 
+    fn close_gracefully(&self, timeout: Duration) -> Future<(), EngineError> {
+        let (tx, f) = Future::pair();
+        if let Err(SendError(PollEvent::Close(tx, _))) = self.0.send(PollEvent::Close(tx, Some(timeout))) {
+            tx.complete(())
+        }
+        f
+    }
+
     fn pause(&self) -> Future<(), EngineError> {
         let (tx, f) = Future::pair();
         if let Err(SendError(PollEvent::Pause(tx))) = self.0.send(PollEvent::Pause(tx)) {
@@ -111,6 +134,14 @@ impl Transport for Polling {
         f
     }
 
+    fn release(&self) -> Future<Pending, EngineError> {
+        let (tx, f) = Future::pair();
+        if let Err(SendError(PollEvent::Release(tx))) = self.0.send(PollEvent::Release(tx)) {
+            tx.fail(EngineError::invalid_state(EVENT_CHANNEL_DISCONNECTED))
+        }
+        f
+    }
+
     fn send(&self, msgs: Vec<Packet>) -> Future<(), EngineError> {
         let (tx, f) = Future::pair();
         if let Err(SendError(PollEvent::Send(_, tx))) = self.0.send(PollEvent::Send(msgs, tx)) {
@@ -130,27 +161,83 @@ impl Transport for Polling {
 
 #[derive(Debug)]
 enum PollEvent {
-    Close(Complete<(), EngineError>),
+    Close(Complete<(), EngineError>, Option<Duration>),
     Start(Complete<(), EngineError>),
     Pause(Complete<(), EngineError>),
+    Release(Complete<Pending, EngineError>),
     Send(Vec<Packet>, Complete<(), EngineError>)
 }
 
-fn handle_polling<C>(url: Url, mut callback: C, cfg: Config, ev_rx: Receiver<PollEvent>, previously_connected: bool)
+/// The outcome of a POST request. Failed requests hand back the sends
+/// they were made of, so that they can be retried once the session has
+/// been resumed.
+enum Post {
+    Failed(Pending, EngineError),
+    Sent
+}
+
+fn handle_polling<C>(url: Url, opts: Options, mut callback: C, cfg: Config, ev_rx: Receiver<PollEvent>, previously_connected: bool)
     where C: FnMut(EngineEvent) + Send + 'static {
     if !previously_connected {
         callback(EngineEvent::Connect(cfg.clone()));
     }
 
+    // Once a request fails due to network issues, the transport stops
+    // making requests and keeps all packets until it is released, so
+    // that the connection can resume the session on a new transport.
+    let mut is_interrupted = false;
     let mut is_paused = false;
-    let mut packet_buffer = Vec::new();
-    let thread_pool = ThreadPool::new_with_name("Engine.io worker thread".to_owned(), 4);
+    // POST requests are made one at a time, so that the packets reach
+    // the server in order and the server never sees overlapping requests.
+    // The packets sent in the meantime stay in the buffer.
+    let mut is_posting = false;
+    // Pausing and releasing wait for the running requests to return, so
+    // that no packets are on their way once the buffer is handed over.
+    let mut pause_tx = None;
+    let mut release_tx = None;
+    let mut packet_buffer: Pending = Vec::new();
+    let (post_tx, post_rx) = channel::<Post>();
+
+    // With a linger window, sends are gathered for a moment before they
+    // are flushed, so that bursts go out in a single request. The end of
+    // the window is signalled by the linger thread.
+    let mut is_lingering = false;
+    let (linger_tx, linger_rx) = channel::<()>();
+    let linger_thread = if opts.linger > Duration::from_millis(0) {
+        Some(ThreadPool::new_with_name("Engine.io linger thread".to_owned(), 1))
+    } else {
+        None
+    };
+
+    let client = match http_client(&opts, &url) {
+        Ok(client) => Arc::new(client),
+        Err(err) => {
+            callback(EngineEvent::Error(err));
+            return;
+        }
+    };
+    // Polls and POST requests run on threads of their own, so that sends
+    // never wait for a long poll to return, unless there's only a single
+    // worker thread.
+    let poll_thread = ThreadPool::new_with_name("Engine.io poll thread".to_owned(), 1);
+    let post_pool = if opts.worker_threads > 1 {
+        Some(ThreadPool::new_with_name("Engine.io POST thread".to_owned(), 1))
+    } else {
+        None
+    };
+    let post_thread = post_pool.as_ref().unwrap_or(&poll_thread);
     loop {
+        // The sender is kept alive for the whole iteration so that the
+        // receiver doesn't hang up (and spin the select) while paused.
         let (pack_tx, pack_rx) = channel();
-        if !is_paused {
+        let is_polling = !is_paused && !is_interrupted;
+        if is_polling {
+            let pack_tx = pack_tx.clone();
             poll_async(
-                &thread_pool,
+                &poll_thread,
+                client.clone(),
                 url.clone(),
+                opts.clone(),
                 cfg.ping_timeout(),
                 Some(cfg.sid().to_owned())
             ).receive(move |res| {
@@ -161,86 +248,356 @@ fn handle_polling<C>(url: Url, mut callback: C, cfg: Config, ev_rx: Receiver<Pol
         loop { select! {
             recv_res = ev_rx.recv() => {
                 match recv_res {
-                    Ok(PollEvent::Close(tx)) => {
+                    Ok(PollEvent::Close(tx, timeout)) => {
+                        let start = Instant::now();
+                        let remaining = |timeout: Duration| {
+                            let elapsed = start.elapsed();
+                            if elapsed < timeout { Some(timeout - elapsed) } else { None }
+                        };
+
+                        // A graceful close flushes the buffer first, one
+                        // POST request after another, so that the close
+                        // packet is the last one to reach the server.
+                        if let Some(timeout) = timeout {
+                            while !is_paused && !is_interrupted && (is_posting || !packet_buffer.is_empty()) {
+                                flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx);
+                                match remaining(timeout).map(|left| post_rx.recv_timeout(left)) {
+                                    Some(Ok(Post::Sent)) => is_posting = false,
+                                    Some(Ok(Post::Failed(batch, err))) => {
+                                        is_posting = false;
+                                        if err.is_transient() {
+                                            is_interrupted = true;
+                                            drop_pending(batch);
+                                        } else {
+                                            fail_batch(batch, err);
+                                        }
+                                    },
+                                    _ => break
+                                }
+                            }
+                        }
+
                         // No async here since we're shutting down anyway
-                        let _ = send(url.clone(), cfg.sid(), vec![Packet::with_str(OpCode::Close, "")]);
+                        if !is_interrupted {
+                            let _ = send(&client, url.clone(), &opts, cfg.sid(), &[Packet::with_str(OpCode::Close, "")]);
+                        }
+                        drop_pending(packet_buffer);
+
+                        // The server answers the running poll request once
+                        // the session has been closed.
+                        if let Some(left) = timeout.and_then(|timeout| remaining(timeout)) {
+                            if let Ok(Ok(packets)) = pack_rx.recv_timeout(left) {
+                                for packet in packets {
+                                    callback(EngineEvent::Message(packet));
+                                }
+                            }
+                        }
+
+                        // Sends that arrived while closing won't go out
+                        // anymore either.
+                        if let Some(tx) = pause_tx.take() {
+                            tx.complete(());
+                        }
+                        if let Some(tx) = release_tx.take() {
+                            tx.complete(Vec::new());
+                        }
+                        let mut late = Vec::new();
+                        while let Ok(ev) = ev_rx.try_recv() {
+                            match ev {
+                                PollEvent::Send(packets, tx) => late.push((packets, tx)),
+                                PollEvent::Release(tx) => tx.complete(Vec::new()),
+                                PollEvent::Close(tx, _) | PollEvent::Pause(tx) | PollEvent::Start(tx) => tx.complete(())
+                            }
+                        }
+                        drop_pending(late);
                         callback(EngineEvent::Disconnect);
                         tx.complete(());
                         return;
                     },
                     Ok(PollEvent::Pause(tx)) => {
                         is_paused = true;
-                        tx.complete(());
+
+                        // Pausing is finished once the running poll and
+                        // POST requests return.
+                        if is_polling || is_posting {
+                            pause_tx = Some(tx);
+                        } else {
+                            tx.complete(());
+                        }
+                    },
+                    Ok(PollEvent::Release(tx)) => {
+                        is_paused = true;
+                        if is_posting {
+                            release_tx = Some(tx);
+                        } else {
+                            tx.complete(packet_buffer.drain(..).collect());
+                            return;
+                        }
                     },
                     Ok(PollEvent::Send(packets, tx)) => {
-                        packet_buffer.push((tx, packets));
-
-                        if !is_paused {
-                            for (tx, packets) in packet_buffer.drain(..) {
-                                send_async(&thread_pool, url.clone(), cfg.sid().to_owned(), packets).receive(|res| {
-                                    match res {
-                                        Ok(_) => tx.complete(()),
-                                        Err(AsyncError::Failed(err)) => tx.fail(err),
-                                        Err(AsyncError::Aborted) => tx.abort()
-                                    }
-                                });
+                        enqueue(&mut packet_buffer, packets, tx);
+
+                        if !is_paused && !is_interrupted {
+                            match linger_thread {
+                                Some(ref linger_thread) if !is_lingering => {
+                                    is_lingering = true;
+                                    let (linger, linger_tx) = (opts.linger, linger_tx.clone());
+                                    linger_thread.execute(move || {
+                                        thread::sleep(linger);
+                                        let _ = linger_tx.send(());
+                                    });
+                                },
+                                Some(_) => {},
+                                None => flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx)
                             }
                         }
+                        evict_oldest(&mut packet_buffer, &opts.buffer_limits);
                     },
                     Ok(PollEvent::Start(tx)) => {
                         is_paused = false;
-                        for (tx, packets) in packet_buffer.drain(..) {
-                            send_async(&thread_pool, url.clone(), cfg.sid().to_owned(), packets).receive(|res| {
-                                match res {
-                                    Ok(_) => tx.complete(()),
-                                    Err(AsyncError::Failed(err)) => tx.fail(err),
-                                    Err(AsyncError::Aborted) => tx.abort()
-                                }
-                            });
+                        if !is_interrupted {
+                            flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx);
                         }
                         tx.complete(());
+
+                        if !is_polling {
+                            break;
+                        }
                     },
-                    _ => return
+                    _ => {
+                        drop_pending(packet_buffer);
+                        return;
+                    }
                 }
             },
             recv_res = pack_rx.recv() => {
                 match recv_res {
                     Ok(Ok(packets)) => {
                         for packet in packets {
+                            // Answer server-side pings (protocol v4) right away.
+                            // The pong is queued so that it doesn't overlap with
+                            // a running POST request, but goes out before the
+                            // packets waiting to be sent.
+                            if packet.opcode() == OpCode::Ping {
+                                let pong = Packet::new(OpCode::Pong, packet.payload().clone());
+                                let (tx, pong_f) = Future::pair();
+                                pong_f.receive(|res| {
+                                    if let Err(AsyncError::Failed(err)) = res {
+                                        let _ = writeln!(&mut ::std::io::stderr(), "Failed to send pong: {:?}", &err);
+                                    }
+                                });
+                                enqueue(&mut packet_buffer, vec![pong], tx);
+                                if !is_paused && !is_interrupted {
+                                    flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx);
+                                }
+                            }
                             callback(EngineEvent::Message(packet));
                         }
+                        if !is_posting {
+                            if let Some(tx) = pause_tx.take() {
+                                tx.complete(());
+                            }
+                        }
                         break;
                     },
                     Ok(Err(AsyncError::Failed(err))) => {
                         let _ = writeln!(&mut ::std::io::stderr(), "Failed to receive packet: {:?}", &err);
-                        callback(EngineEvent::Error(err));
-                        return;
+                        if let Some(tx) = pause_tx.take() {
+                            tx.fail(EngineError::invalid_state(POLL_FAILED_WHILE_PAUSING));
+                        }
+                        if !err.is_transient() {
+                            drop_pending(packet_buffer);
+                            callback(EngineEvent::Error(err));
+                            return;
+                        }
+                        if !is_interrupted {
+                            is_interrupted = true;
+                            callback(EngineEvent::Error(err));
+                        }
+                        break;
                     },
                     _ => {}
                 }
+            },
+            recv_res = post_rx.recv() => {
+                is_posting = false;
+                if let Ok(Post::Failed(mut batch, err)) = recv_res {
+                    if release_tx.is_some() {
+                        // The packets are handed over to be resent by
+                        // the transport taking over.
+                        batch.extend(packet_buffer.drain(..));
+                        packet_buffer = batch;
+                    } else if err.is_transient() {
+                        // Failed packets go before the ones that haven't
+                        // been sent yet to keep them in order.
+                        batch.extend(packet_buffer.drain(..));
+                        packet_buffer = batch;
+                        if !is_interrupted {
+                            is_interrupted = true;
+                            callback(EngineEvent::Error(err));
+                        }
+                    } else {
+                        fail_batch(batch, err);
+                    }
+                }
+                if let Some(tx) = release_tx.take() {
+                    tx.complete(packet_buffer.drain(..).collect());
+                    return;
+                }
+                if !is_polling {
+                    if let Some(tx) = pause_tx.take() {
+                        tx.complete(());
+                    }
+                }
+                if !is_paused && !is_interrupted {
+                    flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx);
+                }
+            },
+            _ = linger_rx.recv() => {
+                is_lingering = false;
+                if !is_paused && !is_interrupted {
+                    flush(post_thread, &client, &url, &opts, cfg.sid(), &mut packet_buffer, &mut is_posting, &post_tx);
+                }
             }
         } }
     }
 }
 
+/// Sends the next batch of buffered packets in the background, unless
+/// a POST request is in flight already. The sends queued up in the
+/// meantime are batched into as few requests as the maximum payload
+/// size allows. The outcome of the request is reported back through
+/// `post_tx`.
+fn flush(tp: &ThreadPool, client: &Arc<Client>, url: &Url, opts: &Options, sid: &str, buffer: &mut Pending, is_posting: &mut bool, post_tx: &Sender<Post>) {
+    if *is_posting || buffer.is_empty() {
+        return;
+    }
+    let batch = take_batch(buffer, opts.max_payload_size);
+    *is_posting = true;
+    let (client, url, opts, sid, post_tx) = (client.clone(), url.clone(), opts.clone(), sid.to_owned(), post_tx.clone());
+    tp.execute(move || {
+        // The sends are resolved here, which may call the callback.
+        mark_transport_thread();
+        let packets: Vec<Packet> = batch.iter().flat_map(|&(ref packets, _)| packets.iter().cloned()).collect();
+        match send(&client, url, &opts, &sid, &packets) {
+            Ok(_) => {
+                for (_, tx) in batch {
+                    tx.complete(());
+                }
+                let _ = post_tx.send(Post::Sent);
+            },
+            Err(err) => {
+                if let Err(SendError(Post::Failed(batch, _))) = post_tx.send(Post::Failed(batch, err)) {
+                    // The transport is gone, so the packets won't
+                    // be sent again.
+                    drop_pending(batch);
+                }
+            }
+        }
+    });
+}
+
+/// Takes the sends that go into the next POST request off the buffer.
+///
+/// Sends are added to the batch as long as the size of their payloads
+/// stays within the maximum payload size, but the first one is always
+/// taken even if it exceeds it on its own.
+fn take_batch(buffer: &mut Pending, max_payload_size: Option<usize>) -> Pending {
+    let mut size = 0;
+    let count = buffer.iter().take_while(|&&(ref packets, _)| {
+        size += Amount::of(packets).bytes;
+        max_payload_size.map_or(true, |max| size <= max)
+    }).count();
+    let count = cmp::max(count, cmp::min(buffer.len(), 1));
+    buffer.drain(..count).collect()
+}
+
+/// Fails the sends of a batch with the error of its POST request.
+///
+/// A single send gets the error itself, several sends share it.
+fn fail_batch(mut batch: Pending, err: EngineError) {
+    if batch.len() == 1 {
+        batch.remove(0).1.fail(err);
+        return;
+    }
+    let err = Arc::new(err);
+    for (_, tx) in batch {
+        tx.fail(EngineError::Shared(err.clone()));
+    }
+}
+
 // ----------------------------------------------------------------------------
 
-fn poll(mut url: Url, timeout: Duration, sid: Option<&str>) -> Result<Vec<Packet>, EngineError> {
-    append_eio_parameters(&mut url, sid);
+/// Creates the HTTP client doing the requests of a transport to the
+/// given URL.
+fn http_client(opts: &Options, url: &Url) -> Result<Client, EngineError> {
+    let mut c = try!(connect_client(opts, url));
+    c.set_read_timeout(Some(opts.request_timeout));
+    c.set_write_timeout(Some(opts.request_timeout));
+    Ok(c)
+}
+
+#[cfg(not(feature = "ssl"))]
+fn connect_client(opts: &Options, url: &Url) -> Result<Client, EngineError> {
+    Ok(match opts.proxy.resolve(url) {
+        Some(proxy) => Client::with_http_proxy(proxy.host().to_owned(), proxy.port()),
+        None => Client::new()
+    })
+}
+
+#[cfg(feature = "ssl")]
+fn connect_client(opts: &Options, url: &Url) -> Result<Client, EngineError> {
+    use hyper::client::ProxyConfig as HttpProxyConfig;
+    use hyper::net::{HttpConnector, HttpsConnector};
+    use proxy::TunnelConnector;
+    use tls::TlsClient;
+
+    let tls = try!(TlsClient::new(&opts.tls));
+    Ok(match opts.proxy.resolve(url) {
+        // The HTTP client can't authenticate the tunnels it opens.
+        Some(ref proxy) if url.scheme() == "https" && proxy.authorization().is_some() => {
+            Client::with_connector(TunnelConnector::new(proxy.clone(), tls, opts.request_timeout))
+        },
+        Some(proxy) => Client::with_proxy_config(HttpProxyConfig::new("http", proxy.host().to_owned(), proxy.port(), HttpConnector, tls)),
+        None => Client::with_connector(HttpsConnector::new(tls))
+    })
+}
+
+fn poll(client: &Client, mut url: Url, opts: &Options, timeout: Duration, sid: Option<&str>) -> Result<Vec<Packet>, EngineError> {
+    append_eio_parameters(&mut url, opts, "polling", sid);
+    if opts.force_jsonp {
+        jsonp::append_index(&mut url);
+    }
     let pre_poll_time = Instant::now();
     loop {
-        match HTTP_CLIENT.get(url.clone()).send() {
-            Ok(response) => return Packet::from_reader_all(&mut BufReader::new(response)),
-            Err(HttpError::Io(ref err)) if err.kind() == ErrorKind::TimedOut && pre_poll_time.elapsed() < timeout => {},
-            Err(err) => return Err(err.into())
+        let response_res = client.get(url.clone()).headers(request_headers(opts, &url)).send().map_err(EngineError::from).and_then(|response| {
+            opts.cookies.store_from(&response.headers);
+            check_status(response)
+        });
+        match response_res {
+            Ok(response) if opts.force_jsonp => return jsonp::decode_response(response, opts.protocol),
+            Ok(response) => {
+                let is_binary = match response.headers.get::<ContentType>() {
+                    Some(&ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, _))) => true,
+                    _ => false
+                };
+                let mut reader = BufReader::new(response);
+                return if is_binary {
+                    Packet::from_reader_all_binary(&mut reader)
+                } else {
+                    Packet::from_reader_all_with(&mut reader, opts.protocol)
+                };
+            },
+            Err(EngineError::Http(HttpError::Io(ref err))) if err.kind() == ErrorKind::TimedOut && pre_poll_time.elapsed() < timeout => {},
+            Err(err) => return Err(err)
         }
     }
 }
 
-fn poll_async(tp: &ThreadPool, url: Url, timeout: Duration, sid: Option<String>) -> Future<Vec<Packet>, EngineError> {
+fn poll_async(tp: &ThreadPool, client: Arc<Client>, url: Url, opts: Options, timeout: Duration, sid: Option<String>) -> Future<Vec<Packet>, EngineError> {
     let (tx, f) = Future::pair();
     tp.execute(move || {
-        let poll_res = poll(url, timeout, match sid {
+        let poll_res = poll(&client, url, &opts, timeout, match sid {
             Some(ref string) => Some(string),
             None => None
         });
@@ -252,36 +609,57 @@ fn poll_async(tp: &ThreadPool, url: Url, timeout: Duration, sid: Option<String>)
     f
 }
 
-fn send(mut url: Url, sid: &str, packets: Vec<Packet>) -> Result<(), EngineError> {
-    append_eio_parameters(&mut url, Some(sid));
+fn send(client: &Client, mut url: Url, opts: &Options, sid: &str, packets: &[Packet]) -> Result<(), EngineError> {
+    append_eio_parameters(&mut url, opts, "polling", Some(sid));
+    if opts.force_jsonp {
+        jsonp::append_index(&mut url);
+        return jsonp::send(client, url, opts, packets);
+    }
 
+    // The binary payload encoding is only worth it when there is binary data.
+    let is_binary = opts.binary_payloads() && packets.iter().any(|p| match *p.payload() {
+        Payload::Binary(_) => true,
+        Payload::String(_) => false
+    });
     let capacity = packets.iter().fold(0usize, |val, p| val + p.try_compute_length(false).unwrap_or(0usize));
-    let mut buf = Cursor::new(vec![0; capacity]);
-    for packet in packets {
-        try!(packet.write_payload_to(&mut buf));
+    let mut buf = Cursor::new(Vec::with_capacity(capacity));
+    if is_binary {
+        for packet in packets {
+            try!(packet.write_payload_binary_to(&mut buf));
+        }
+    } else {
+        try!(Packet::write_all_to(packets, &mut buf, opts.protocol));
     }
     let buf: &[_] = &buf.into_inner();
 
-    match HTTP_CLIENT.post(url).body(buf).send() {
-        Ok(_) => Ok(()),
+    let headers = request_headers(opts, &url);
+    let mut request = client.post(url).headers(headers).body(buf);
+    if is_binary {
+        request = request.header(ContentType(Mime(TopLevel::Application, SubLevel::OctetStream, vec![])));
+    }
+    match request.send() {
+        Ok(response) => {
+            opts.cookies.store_from(&response.headers);
+            check_status(response).map(|_| ())
+        },
         Err(err) => Err(err.into())
     }
 }
 
-fn send_async(tp: &ThreadPool, url: Url, sid: String, packets: Vec<Packet>) -> Future<(), EngineError> {
-    let (tx, f) = Future::pair();
-    tp.execute(move || {
-        match send(url, &sid, packets){
-            Ok(_) => tx.complete(()),
-            Err(err) => tx.fail(err)
-        }
-    });
-    f
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
+    use super::{fail_batch, take_batch, PollEvent};
+    use ::{BufferLimits, Connection, EngineError, EngineEvent, OpCode, OverflowPolicy, Packet};
+    use eventual::{Async, AsyncError, Future};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::serve;
+    use transports::{enqueue, evict_oldest, Options, Pending, Transport};
+    use url::Url;
 
     #[test]
     fn connection() {
@@ -289,11 +667,11 @@ mod test {
         use std::sync::mpsc::channel;
         use std::time::Duration;
         use eventual::*;
-        use transports::Transport;
+        use transports::{Options, Transport};
         use url::Url;
 
         let (tx, rx) = channel();
-        let p = Polling::new(Url::parse("http://festify.us:5002/engine.io/").unwrap(), move |ev| {
+        let p = Polling::new(Url::parse("http://festify.us:5002/engine.io/").unwrap(), Options::default(), move |ev| {
             match ev {
                 EngineEvent::Connect(_) => tx.send("connect".to_owned()).unwrap(),
                 EngineEvent::ConnectError(_) => tx.send("connect_error".to_owned()).unwrap(),
@@ -311,4 +689,215 @@ mod test {
         ::std::thread::sleep(Duration::from_millis(5000));
         p.close().await().unwrap();
     }
+
+    #[test]
+    fn handshake_deadline() {
+        // The server accepts the connection, but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/engine.io/", listener.local_addr().unwrap())).unwrap();
+        let mut opts = Options::default();
+        opts.handshake_timeout = Duration::from_millis(200);
+        opts.request_timeout = Duration::from_secs(30);
+
+        let start = Instant::now();
+        match Polling::new(url, opts, |_| {}).await() {
+            Err(AsyncError::Failed(EngineError::Timeout)) => {},
+            res => panic!("Expected the handshake to time out, got {:?}.", res.map(|_| ()))
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn batching() {
+        let mut buffer: Pending = ["Hello", "World", "Hello World", "!"].iter().map(|payload| {
+            (vec![Packet::with_str(OpCode::Message, payload)], Future::pair().0)
+        }).collect();
+
+        assert_eq!(take_batch(&mut buffer, Some(10)).len(), 2);
+        // Sends exceeding the maximum on their own go out alone.
+        assert_eq!(take_batch(&mut buffer, Some(10)).len(), 1);
+        assert_eq!(take_batch(&mut buffer, None).len(), 1);
+        assert!(take_batch(&mut buffer, None).is_empty());
+    }
+
+    #[test]
+    fn heartbeat_eviction() {
+        let limits = BufferLimits {
+            max_packets: Some(1),
+            policy: OverflowPolicy::DropOldest,
+            ..Default::default()
+        };
+        let mut buffer: Pending = Vec::new();
+        enqueue(&mut buffer, vec![Packet::with_str(OpCode::Message, "Hello")], Future::pair().0);
+        enqueue(&mut buffer, vec![Packet::with_str(OpCode::Pong, "")], Future::pair().0);
+        enqueue(&mut buffer, vec![Packet::with_str(OpCode::Message, "World")], Future::pair().0);
+        enqueue(&mut buffer, vec![Packet::with_str(OpCode::Ping, "")], Future::pair().0);
+
+        // The heartbeat packets are queued in front of the others, and
+        // neither count towards the limits nor are dropped.
+        assert_eq!(evict_oldest(&mut buffer, &limits), 1);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer[0].0, vec![Packet::with_str(OpCode::Pong, "")]);
+        assert_eq!(buffer[1].0, vec![Packet::with_str(OpCode::Ping, "")]);
+        assert_eq!(buffer[2].0, vec![Packet::with_str(OpCode::Message, "World")]);
+    }
+
+    #[test]
+    fn batch_failure() {
+        let (txs, fs): (Vec<_>, Vec<_>) = (0..3).map(|_| Future::pair()).unzip();
+        let batch = txs.into_iter().map(|tx| (vec![Packet::with_str(OpCode::Message, "Hello")], tx)).collect();
+        fail_batch(batch, EngineError::Timeout);
+
+        // Every send sees the error of the request.
+        for f in fs {
+            match f.await() {
+                Err(AsyncError::Failed(EngineError::Shared(ref err))) => match **err {
+                    EngineError::Timeout => {},
+                    ref err => panic!("Unexpected error {:?}.", err)
+                },
+                res => panic!("Expected a shared error, got {:?}.", res)
+            }
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let p = Polling::new(serve(posted.clone(), true), Options::default(), |_| {}).await().unwrap();
+
+        // Several threads send at once without waiting for their packets
+        // to arrive, every thread's packets must arrive in order.
+        let senders: Vec<_> = (0..4).map(|id| {
+            let ev_tx = p.0.clone();
+            thread::spawn(move || {
+                (0..10).map(|index| {
+                    let (tx, f) = Future::pair();
+                    let packet = Packet::with_string(OpCode::Message, format!("{}-{}", id, index));
+                    ev_tx.send(PollEvent::Send(vec![packet], tx)).unwrap();
+                    f
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        for sender in senders {
+            for f in sender.join().unwrap() {
+                f.await().unwrap();
+            }
+        }
+        p.close().await().unwrap();
+
+        let posted = posted.lock().unwrap().clone().unwrap();
+        assert_eq!(posted.len(), 40);
+        for id in 0..4 {
+            let prefix = format!("{}-", id);
+            let indices: Vec<usize> = posted.iter()
+                .filter(|payload| payload.starts_with(&prefix))
+                .map(|payload| payload[prefix.len()..].parse().unwrap())
+                .collect();
+            assert_eq!(indices, (0..10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn single_worker() {
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let mut opts = Options::default();
+        opts.worker_threads = 1;
+        let p = Polling::new(serve(posted.clone(), true), opts, |_| {}).await().unwrap();
+
+        // The sends go out once the running poll has returned.
+        for index in 0..3 {
+            p.send(vec![Packet::with_string(OpCode::Message, index.to_string())]).await().unwrap();
+        }
+        p.close().await().unwrap();
+        assert_eq!(posted.lock().unwrap().clone().unwrap(), vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn graceful_close() {
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let p = Polling::new(serve(posted.clone(), true), Options::default(), |_| {}).await().unwrap();
+
+        let sends: Vec<_> = (0..10).map(|index| {
+            p.send(vec![Packet::with_string(OpCode::Message, index.to_string())])
+        }).collect();
+        p.close_gracefully(Duration::from_secs(5)).await().unwrap();
+        for f in sends {
+            f.await().unwrap();
+        }
+
+        let posted = posted.lock().unwrap().clone().unwrap();
+        assert_eq!(posted, (0..10).map(|index| index.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drain_and_drop() {
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let (tx, rx) = channel();
+        let connection = Connection::new();
+        connection.connect(serve(posted.clone(), true), Box::new(move |ev| {
+            if let EngineEvent::Drain = ev {
+                let _ = tx.send(());
+            }
+        })).await().unwrap();
+
+        // Drain fires once the sent packets have been flushed.
+        connection.send_all(vec![Packet::with_str(OpCode::Message, "Hello")]).await().unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).expect("Drain wasn't fired after flushing.");
+
+        // The first send is being posted when the connection is closed,
+        // the ones after it are still waiting.
+        let sends: Vec<_> = (0..5).map(|index| {
+            connection.send_all(vec![Packet::with_string(OpCode::Message, index.to_string())])
+        }).collect();
+        connection.disconnect().await().unwrap();
+
+        let mut dropped = 0;
+        for (index, f) in sends.into_iter().enumerate() {
+            match f.await() {
+                Ok(_) => {},
+                Err(AsyncError::Failed(EngineError::Dropped(packets))) => {
+                    assert_eq!(packets, vec![Packet::with_string(OpCode::Message, index.to_string())]);
+                    dropped += 1;
+                },
+                res => panic!("Expected the send to be dropped, got {:?}.", res)
+            }
+        }
+        assert!(dropped >= 4, "Only {} sends have been dropped.", dropped);
+    }
+
+    #[test]
+    fn release_posting() {
+        // POST requests never return, so the one in flight times out.
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let mut opts = Options::default();
+        opts.request_timeout = Duration::from_millis(500);
+        let p = Polling::new(serve(posted, false), opts, |_| {}).await().unwrap();
+
+        let send_f = p.send(vec![Packet::with_str(OpCode::Message, "Hello")]);
+        thread::sleep(Duration::from_millis(50));
+        p.pause().await().unwrap();
+
+        // The failed send is handed back rather than dropped.
+        let pending = p.release().await().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, vec![Packet::with_str(OpCode::Message, "Hello")]);
+        drop(pending);
+        assert!(send_f.await().is_err());
+    }
+
+    #[test]
+    fn graceful_close_late() {
+        let posted = Arc::new(Mutex::new(Ok(Vec::new())));
+        let p = Polling::new(serve(posted.clone(), true), Options::default(), |_| {}).await().unwrap();
+
+        let close_f = p.close_gracefully(Duration::from_secs(5));
+        let send_f = p.send(vec![Packet::with_str(OpCode::Message, "Hello")]);
+        close_f.await().unwrap();
+        match send_f.await() {
+            Err(AsyncError::Failed(EngineError::Dropped(packets))) => assert_eq!(packets, vec![Packet::with_str(OpCode::Message, "Hello")]),
+            res => panic!("Expected the send to be dropped, got {:?}.", res)
+        }
+        assert!(posted.lock().unwrap().clone().unwrap().is_empty());
+    }
 }
\ No newline at end of file
@@ -4,63 +4,167 @@
 //! much less stable. Lots of company firewalls block websocket
 //! traffic, so this library (and engine.io) takes great care to
 //! only use them when they can be used properly.
+//!
+//! Before a socket carries any session traffic it is probed: the
+//! client sends a `2probe` ping and waits for the server to answer
+//! with `3probe`. Only then the polling transport is paused and the
+//! session is upgraded through an `Upgrade` packet.
+//!
+//! Where websockets are known to work, a session can also be set up
+//! through a websocket right away. The server then sends the `Open`
+//! packet as the first frame.
 
-// TODO: Make use of this
-
-use super::{append_eio_parameters, Config, Transport};
+use super::{append_eio_parameters, drop_pending, enqueue, evict_oldest, request_headers, with_deadline, Config, Options, Pending, Transport};
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use ::{EngineError, EngineEvent, Packet};
+use ::{EngineError, EngineEvent, OpCode, Packet, Payload, Protocol};
+use proxy::{relay, RELAY_TOKEN_HEADER};
+#[cfg(feature = "ssl")]
+use proxy::relay_tls;
+use eventual::{Async, AsyncError, Complete, Future};
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
+use outgoing::mark_transport_thread;
+use rustc_serialize::json::decode;
 use url::Url;
-use ws::{Builder, CloseCode, Error as WsError, Factory, Handler, Message, Result as WsResult, Sender as WsSender, Settings};
+use ws::{Builder, CloseCode, Error as WsError, Factory, Handler, Handshake, Message, Request, Response, Result as WsResult, Sender as WsSender, Settings};
 
-const CALLBACK_POISONED: &'static str = "Websocket callback lock poisoned.";
+const BUFFER_POISONED: &'static str = "Websocket send buffer lock poisoned.";
+const OPEN_CLOSED: &'static str = "The websocket was closed before the session has been opened.";
+const OPEN_UNEXPECTED_PACKET: &'static str = "The server sent an unexpected packet instead of opening the session.";
+const PROBE_CLOSED: &'static str = "The websocket was closed before the probe has been answered.";
+const PROBE_PAYLOAD: &'static str = "probe";
+const PROBE_UNEXPECTED_PACKET: &'static str = "The server answered the websocket probe with an unexpected packet.";
+const STATE_POISONED: &'static str = "Websocket state lock poisoned.";
+#[cfg(not(feature = "ssl"))]
+const WSS_UNSUPPORTED: &'static str = "Secure websockets require the ssl feature.";
 
 /// The websockets transport.
 pub struct Socket {
-    ct_rx: Receiver<()>,
-    is_paused: bool,
-    sender: WsSender
+    buffer: Mutex<Pending>,
+    cfg: Config,
+    is_paused: AtomicBool,
+    protocol: Protocol,
+    sender: WsSender,
+    state: Arc<Mutex<SocketState>>
 }
 
 impl Socket {
-    /// Creates a new instance of a websocket transport from a given
-    /// configuration and automatically connects to the endpoint.
+    /// Creates a new instance of a websocket transport and sets up a new
+    /// session through it, skipping the polling handshake.
+    ///
+    /// The returned future resolves once the server has opened the session.
+    ///
+    /// ## Parameters
+    /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
+    ///   of the server to connect to. HTTP(S) URLs are converted to their
+    ///   WS(S) counterpart.
+    /// - `opts: Options`: The options of the transport.
+    /// - `callback: C`: Callback to call when asynchronous events are ready.
+    pub fn connect<C: FnMut(EngineEvent) + Send + 'static>(mut url: Url, opts: Options, callback: C) -> Future<Socket, EngineError> {
+        set_ws_scheme(&mut url);
+        append_eio_parameters(&mut url, &opts, "websocket", None);
+        let protocol = opts.protocol;
+        let timeout = opts.handshake_timeout;
+
+        let (open_tx, open_f) = Future::pair();
+        let state = Arc::new(Mutex::new(SocketState {
+            callback: Box::new(callback),
+            is_upgraded: false,
+            open_tx: Some(open_tx),
+            opts: opts,
+            probe_tx: None,
+            protocol: protocol,
+            relay_token: None,
+            request_url: None
+        }));
+        let sender = match spawn(url, state.clone()) {
+            Ok(sender) => sender,
+            Err(err) => return Future::error(err)
+        };
+
+        // The socket is closed if the server doesn't open the session in time.
+        let closer = sender.clone();
+        with_deadline(open_f, timeout, move || {
+            let _ = closer.close(CloseCode::Normal);
+        }).map(move |cfg| {
+            Socket {
+                buffer: Mutex::new(Vec::new()),
+                cfg: cfg,
+                is_paused: AtomicBool::new(false),
+                protocol: protocol,
+                sender: sender,
+                state: state
+            }
+        })
+    }
+
+    /// Creates a new instance of a websocket transport for an existing
+    /// session and probes whether the server can be reached through it.
+    ///
+    /// The returned future resolves once the server has answered the
+    /// probe. Until `upgrade` is called, no events are fired through
+    /// the callback.
     ///
     /// ## Parameters
     /// - `url: Url`: The _full_ URL (i.e. including the `/engine.io/`-path)
-    ///   of the server to connect to.
+    ///   of the server to connect to. HTTP(S) URLs are converted to their
+    ///   WS(S) counterpart.
+    /// - `opts: Options`: The options of the transport.
     /// - `callback: C`: Callback to call when asynchronous events are ready.
     /// - `cfg: Config`: A transport configuration used to initialize session.
-    pub fn new<C: FnMut(EngineEvent) + Send + 'static>(mut url: Url, callback: C, cfg: Config) -> Socket {
-        append_eio_parameters(&mut url, Some(cfg.sid()));
+    pub fn probe<C: FnMut(EngineEvent) + Send + 'static>(mut url: Url, opts: Options, callback: C, cfg: Config) -> Future<Socket, EngineError> {
+        set_ws_scheme(&mut url);
+        append_eio_parameters(&mut url, &opts, "websocket", Some(cfg.sid()));
+        let protocol = opts.protocol;
 
-        let (ct_tx, ct_rx) = channel();
-        let mut ws = Builder::new().with_settings(Settings {
-            key_strict: true,
-            ..Default::default()
-        }).build(SocketHandler::new(callback)).expect("Failed to set up websocket.");
-        let broadcaster = ws.broadcaster();
+        let (probe_tx, probe_f) = Future::pair();
+        let state = Arc::new(Mutex::new(SocketState {
+            callback: Box::new(callback),
+            is_upgraded: false,
+            open_tx: None,
+            opts: opts,
+            probe_tx: Some(probe_tx),
+            protocol: protocol,
+            relay_token: None,
+            request_url: None
+        }));
+        let sender = match spawn(url, state.clone()) {
+            Ok(sender) => sender,
+            Err(err) => return Future::error(err)
+        };
 
-        ws.connect(url).expect("Failed to enqueue websocket connection.");
-        thread::spawn(move || {
-            ws.run().expect("Failed to run mio event loop.");
-            let _ = ct_tx.send(());
-        });
+        let socket = Socket {
+            buffer: Mutex::new(Vec::new()),
+            cfg: cfg,
+            is_paused: AtomicBool::new(false),
+            protocol: protocol,
+            sender: sender,
+            state: state
+        };
+        probe_f.map(move |_| socket)
+    }
 
-        Socket {
-            ct_rx: ct_rx,
-            is_paused: false,
-            sender: broadcaster
-        }
+    /// Gets the configuration associated with the transport.
+    pub fn cfg(&self) -> &Config {
+        &self.cfg
     }
 
-    fn do_send(&mut self, msgs: Vec<Packet>) -> Result<(), EngineError> {
+    /// Upgrades the session to this socket by sending the `Upgrade`
+    /// packet. From now on, events are fired through the callback.
+    pub fn upgrade(&self) -> Result<(), EngineError> {
+        self.state.lock().expect(STATE_POISONED).is_upgraded = true;
+        self.sender.send(Packet::with_str(OpCode::Upgrade, "")).map_err(|err| err.into())
+    }
+
+    fn do_send(&self, msgs: Vec<Packet>) -> Result<(), EngineError> {
         for packet in msgs {
-            try!(self.sender.send(packet));
+            try!(self.sender.send(to_message(packet, self.protocol)));
         }
         Ok(())
     }
@@ -68,81 +172,458 @@ impl Socket {
 
 impl Debug for Socket {
     fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
-        write!(formatter, "Socket {{ is_paused: {}, ... }}", self.is_paused)
+        write!(formatter, "Socket {{ is_paused: {}, ... }}", self.is_paused.load(Ordering::SeqCst))
     }
 }
 
 impl Drop for Socket {
     fn drop(&mut self) {
-        let _ = self.close();
+        let _ = self.sender.close(CloseCode::Normal);
     }
 }
 
 impl Transport for Socket {
-    fn close(&self) -> Result<(), EngineError> {
-        self.sender.shutdown().map_err(|err| err.into())
+    fn close(&self) -> Future<(), EngineError> {
+        drop_pending(mem::replace(self.buffer.lock().expect(BUFFER_POISONED).deref_mut(), Vec::new()));
+        match self.sender.close(CloseCode::Normal) {
+            Ok(_) => Future::of(()),
+            Err(err) => Future::error(err.into())
+        }
     }
 
-    fn pause(&self) -> Result<(), EngineError> {
-        self.is_paused = true;
-        Ok(())
+    fn pause(&self) -> Future<(), EngineError> {
+        self.is_paused.store(true, Ordering::SeqCst);
+        Future::of(())
+    }
+
+    fn release(&self) -> Future<Pending, EngineError> {
+        self.state.lock().expect(STATE_POISONED).is_upgraded = false;
+        let _ = self.sender.shutdown();
+        let pending = mem::replace(self.buffer.lock().expect(BUFFER_POISONED).deref_mut(), Vec::new());
+        Future::of(pending)
     }
 
-    fn send(&self, msgs: Vec<Packet>) -> Result<(), EngineError> {
-        if !self.is_paused {
-            try!(self.do_send(msgs))
+    fn send(&self, msgs: Vec<Packet>) -> Future<(), EngineError> {
+        if self.is_paused.load(Ordering::SeqCst) {
+            let limits = self.state.lock().expect(STATE_POISONED).opts.buffer_limits.clone();
+            let (tx, f) = Future::pair();
+            let mut buffer = self.buffer.lock().expect(BUFFER_POISONED);
+            enqueue(&mut buffer, msgs, tx);
+            evict_oldest(&mut buffer, &limits);
+            f
+        } else {
+            match self.do_send(msgs) {
+                Ok(_) => Future::of(()),
+                Err(err) => Future::error(err)
+            }
         }
-        Ok(())
     }
 
-    fn start(&self) -> Result<(), EngineError> {
-        self.is_paused = false;
-        Ok(())
+    fn start(&self) -> Future<(), EngineError> {
+        self.is_paused.store(false, Ordering::SeqCst);
+        let pending = mem::replace(self.buffer.lock().expect(BUFFER_POISONED).deref_mut(), Vec::new());
+        for (packets, tx) in pending {
+            self.send(packets).receive(|res| {
+                match res {
+                    Ok(_) => tx.complete(()),
+                    Err(AsyncError::Failed(err)) => tx.fail(err),
+                    Err(AsyncError::Aborted) => tx.abort()
+                }
+            });
+        }
+        Future::of(())
     }
 }
 
-struct SocketHandler<C>(Arc<Mutex<C>>);
+struct SocketState {
+    callback: Box<FnMut(EngineEvent) + Send + 'static>,
+    is_upgraded: bool,
+    open_tx: Option<Complete<Config, EngineError>>,
+    opts: Options,
+    probe_tx: Option<Complete<(), EngineError>>,
+    protocol: Protocol,
+    relay_token: Option<String>,
+    request_url: Option<Url>
+}
+
+/// The handler of the underlying websocket connection.
+///
+/// Events are only forwarded to the callback after the session has
+/// been upgraded or opened. Before that, they resolve the probe or the
+/// opening of the session instead.
+#[derive(Clone)]
+struct SocketHandler(Arc<Mutex<SocketState>>, Option<WsSender>);
 
-impl<C> SocketHandler<C> {
-    pub fn new(callback: C) -> Self {
-        SocketHandler(Arc::new(Mutex::new(callback)))
+impl SocketHandler {
+    fn dispatch(&self, ev: EngineEvent) {
+        let mut guard = self.0.lock().expect(STATE_POISONED);
+        if guard.is_upgraded {
+            guard.callback.deref_mut()(ev);
+        }
+    }
+
+    fn fail(&self, err: EngineError) {
+        let mut guard = self.0.lock().expect(STATE_POISONED);
+        if let Some(tx) = guard.open_tx.take() {
+            tx.fail(err);
+        } else if let Some(tx) = guard.probe_tx.take() {
+            tx.fail(err);
+        } else if guard.is_upgraded {
+            guard.callback.deref_mut()(EngineEvent::Error(err));
+        }
+    }
+
+    fn on_packet(&self, packet: Packet) {
+        let open_tx = self.0.lock().expect(STATE_POISONED).open_tx.take();
+        if let Some(tx) = open_tx {
+            self.on_open_packet(packet, tx);
+            return;
+        }
+
+        let probe_tx = self.0.lock().expect(STATE_POISONED).probe_tx.take();
+        match probe_tx {
+            Some(tx) => {
+                if packet == Packet::with_str(OpCode::Pong, PROBE_PAYLOAD) {
+                    tx.complete(());
+                } else {
+                    tx.fail(EngineError::invalid_state(PROBE_UNEXPECTED_PACKET));
+                }
+            },
+            None => {
+                // Answer server-side pings (protocol v4) right away.
+                if packet.opcode() == OpCode::Ping {
+                    if let Some(ref sender) = self.1 {
+                        let pong = Packet::new(OpCode::Pong, packet.payload().clone());
+                        let _ = sender.send(to_message(pong, self.protocol()));
+                    }
+                }
+                self.dispatch(EngineEvent::Message(packet))
+            }
+        }
+    }
+
+    fn on_open_packet(&self, packet: Packet, tx: Complete<Config, EngineError>) {
+        let cfg_res = match (packet.opcode(), packet.payload()) {
+            (OpCode::Open, &Payload::String(ref str)) => decode::<Config>(str).map_err(|err| err.into()),
+            _ => Err(EngineError::invalid_state(OPEN_UNEXPECTED_PACKET))
+        };
+        match cfg_res {
+            Ok(cfg) => {
+                self.0.lock().expect(STATE_POISONED).is_upgraded = true;
+                self.dispatch(EngineEvent::Connect(cfg.clone()));
+                tx.complete(cfg);
+            },
+            Err(err) => tx.fail(err)
+        }
     }
-}
 
-impl<C> ::std::clone::Clone for SocketHandler<C> { // #[derive(Clone)] doesn't work
-    fn clone(&self) -> Self {
-        SocketHandler(self.0.clone())
+    fn protocol(&self) -> Protocol {
+        self.0.lock().expect(STATE_POISONED).protocol
     }
 }
 
-impl<C> Factory for SocketHandler<C>
-    where C: FnMut(EngineEvent) + Send + 'static {
+impl Factory for SocketHandler {
     type Handler = Self;
 
-    fn connection_made(&mut self, _: WsSender) -> Self::Handler {
-        self.clone()
+    fn connection_made(&mut self, sender: WsSender) -> Self::Handler {
+        SocketHandler(self.0.clone(), Some(sender))
     }
 }
 
-impl<C> Handler for SocketHandler<C>
-    where C: FnMut(EngineEvent) + Send + 'static {
+impl Handler for SocketHandler {
+    fn build_request(&mut self, url: &Url) -> WsResult<Request> {
+        let state = self.0.lock().expect(STATE_POISONED);
+
+        // Behind a proxy, the socket connects to the local relay, but
+        // the request is meant for the server.
+        let mut request = try!(Request::from_url(state.request_url.as_ref().unwrap_or(url)));
+        let headers = request_headers(&state.opts, url);
+        for header in headers.iter() {
+            request.headers_mut().push((header.name().to_owned(), header.value_string().into_bytes()));
+        }
+        if let Some(ref token) = state.relay_token {
+            request.headers_mut().push((RELAY_TOKEN_HEADER.to_owned(), token.clone().into_bytes()));
+        }
+        Ok(request)
+    }
+
+    #[cfg(feature = "ssl")]
+    fn build_ssl(&mut self) -> WsResult<::openssl::ssl::Ssl> {
+        let state = self.0.lock().expect(STATE_POISONED);
+        let host = state.request_url.as_ref().and_then(|url| url.host_str()).unwrap_or("").to_owned();
+        let ctx = try!(state.opts.tls.context());
+        state.opts.tls.ssl(&ctx, &host).map_err(|err| err.into())
+    }
+
+    fn on_response(&mut self, response: &Response) -> WsResult<()> {
+        let cookies = self.0.lock().expect(STATE_POISONED).opts.cookies.clone();
+        for &(ref name, ref value) in response.headers() {
+            if name.to_lowercase() == "set-cookie" {
+                cookies.store(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_open(&mut self, _: Handshake) -> WsResult<()> {
+        let is_probing = self.0.lock().expect(STATE_POISONED).probe_tx.is_some();
+        match self.1 {
+            Some(ref sender) if is_probing => sender.send(Packet::with_str(OpCode::Ping, PROBE_PAYLOAD)),
+            _ => Ok(())
+        }
+    }
+
     fn on_close(&mut self, _: CloseCode, _: &str) {
-        let mut guard = self.0.lock().expect(CALLBACK_POISONED);
-        guard.deref_mut()(EngineEvent::Disconnect);
+        let (open_tx, probe_tx) = {
+            let mut guard = self.0.lock().expect(STATE_POISONED);
+            (guard.open_tx.take(), guard.probe_tx.take())
+        };
+        match (open_tx, probe_tx) {
+            (Some(tx), _) => tx.fail(EngineError::invalid_state(OPEN_CLOSED)),
+            (_, Some(tx)) => tx.fail(EngineError::invalid_state(PROBE_CLOSED)),
+            (None, None) => self.dispatch(EngineEvent::Disconnect)
+        }
     }
 
     fn on_error(&mut self, err: WsError) {
-        let mut guard = self.0.lock().expect(CALLBACK_POISONED);
-        guard.deref_mut()(EngineEvent::Error(&EngineError::WebSocket(err)));
+        self.fail(EngineError::WebSocket(err));
     }
 
     fn on_message(&mut self, msg: Message) -> WsResult<()> {
-        if let Message::Text(str) = msg {
-            if let Ok(pck) = Packet::from_str(&str) {
-                let mut guard = self.0.lock().expect(CALLBACK_POISONED);
-                guard.deref_mut()(EngineEvent::Message(&pck));
+        match msg {
+            Message::Text(str) => {
+                if let Ok(pck) = Packet::from_str_with(&str, self.protocol()) {
+                    self.on_packet(pck);
+                }
+            },
+            Message::Binary(data) => {
+                match self.protocol() {
+                    Protocol::V3 => {
+                        if let Some((&opcode, data)) = data.split_first() {
+                            if let Ok(opcode) = OpCode::from_u8(opcode) {
+                                self.on_packet(Packet::new(opcode, Payload::Binary(data.to_vec())));
+                            }
+                        }
+                    },
+                    Protocol::V4 => self.on_packet(Packet::with_binary(OpCode::Message, data))
+                }
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Converts HTTP(S) URLs to their WS(S) counterpart.
+fn set_ws_scheme(url: &mut Url) {
+    let scheme = match url.scheme() {
+        "https" | "wss" => "wss",
+        _ => "ws"
+    };
+    let _ = url.set_scheme(scheme);
+}
+
+/// Starts the relay forwarding the socket through the given tunnel,
+/// doing TLS with the server for secure websockets.
+#[cfg(feature = "ssl")]
+fn relay_tunnel(tunnel: TcpStream, url: &Url, opts: &Options) -> Result<(SocketAddr, String), EngineError> {
+    let timeout = opts.request_timeout;
+    if url.scheme() != "wss" {
+        return relay(tunnel, timeout).map_err(From::from);
+    }
+
+    let host = url.host_str().unwrap_or("");
+    let ssl = try!(opts.tls.context().and_then(|ctx| opts.tls.ssl(&ctx, host)).map_err(EngineError::invalid_config));
+    try!(tunnel.set_read_timeout(Some(timeout)));
+    try!(tunnel.set_write_timeout(Some(timeout)));
+    let stream = try!(SslStream::connect(ssl, tunnel).map_err(|err| EngineError::Http(err.into())));
+    try!(stream.get_ref().set_write_timeout(None));
+    relay_tls(stream, timeout).map_err(From::from)
+}
+
+/// Starts the relay forwarding the socket through the given tunnel.
+#[cfg(not(feature = "ssl"))]
+fn relay_tunnel(tunnel: TcpStream, url: &Url, opts: &Options) -> Result<(SocketAddr, String), EngineError> {
+    if url.scheme() == "wss" {
+        return Err(EngineError::invalid_config(WSS_UNSUPPORTED));
+    }
+    relay(tunnel, opts.request_timeout).map_err(From::from)
+}
+
+/// Opens the underlying websocket connection and runs it on a
+/// background thread.
+///
+/// If the server is reached through a proxy, the connection is tunneled
+/// through it via a local relay.
+fn spawn(url: Url, state: Arc<Mutex<SocketState>>) -> Result<WsSender, EngineError> {
+    let (proxy, opts) = {
+        let mut state = state.lock().expect(STATE_POISONED);
+        state.request_url = Some(url.clone());
+        (state.opts.proxy.resolve(&url), state.opts.clone())
+    };
+    let url = match proxy {
+        Some(proxy) => {
+            let host = url.host_str().unwrap_or("").to_owned();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let tunnel = try!(proxy.tunnel(&host, port, opts.request_timeout));
+            let (addr, token) = try!(relay_tunnel(tunnel, &url, &opts));
+            state.lock().expect(STATE_POISONED).relay_token = Some(token);
+
+            // The relay does TLS with the server, so the socket speaks
+            // plain websocket to it.
+            let mut relay_url = url.clone();
+            let _ = relay_url.set_scheme("ws");
+            let _ = relay_url.set_ip_host(addr.ip());
+            let _ = relay_url.set_port(Some(addr.port()));
+            relay_url
+        },
+        None => url
+    };
+
+    let handler = SocketHandler(state, None);
+    let mut ws = try!(Builder::new().with_settings(Settings {
+        key_strict: true,
+        ..Default::default()
+    }).build(handler.clone()));
+    let broadcaster = ws.broadcaster();
+
+    try!(ws.connect(url));
+    thread::spawn(move || {
+        mark_transport_thread();
+        if let Err(err) = ws.run() {
+            handler.fail(err.into());
+        }
+    });
+    Ok(broadcaster)
+}
+
+/// Converts a packet into a websocket message of the given protocol version.
+///
+/// Since version 4, binary frames carry the raw message data without
+/// an opcode byte.
+fn to_message(packet: Packet, protocol: Protocol) -> Message {
+    if let (Protocol::V4, &Payload::Binary(ref data)) = (protocol, packet.payload()) {
+        return Message::Binary(data.clone());
+    }
+    packet.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::{Proxy, ProxyConfig};
+    use std::io::{copy, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+    use proxy::read_head;
+
+    /// Starts a proxy tunneling a single `CONNECT` request to the given
+    /// address, whatever host the request names.
+    fn serve_proxy(target: SocketAddr) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            assert!(read_head(&mut client).unwrap().starts_with("CONNECT localhost:"));
+            let server = TcpStream::connect(target).unwrap();
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+
+            let (mut client_read, mut server_write) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || {
+                let _ = copy(&mut client_read, &mut server_write);
+            });
+            let (mut server_read, mut client_write) = (server, client);
+            let _ = copy(&mut server_read, &mut client_write);
+        });
+        Proxy::new("127.0.0.1", port)
+    }
+
+    /// Answers the websocket handshake and opens the session, reporting
+    /// the request head the server has received.
+    fn open_session<S: Read + Write>(stream: &mut S, head_tx: Sender<String>) {
+        let head = read_head(stream).unwrap();
+        let request = Request::parse(head.as_bytes()).unwrap().unwrap();
+        Response::from_request(&request).unwrap().format(stream).unwrap();
+
+        let open = r#"0{"sid":"test","upgrades":[],"pingInterval":25000,"pingTimeout":5000}"#;
+        stream.write_all(&[0x81, open.len() as u8]).unwrap();
+        stream.write_all(open.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        head_tx.send(head).unwrap();
+
+        // Keep the socket open until the client goes away.
+        let _ = stream.read(&mut [0u8; 1]);
+    }
+
+    /// Connects a socket to the given server through the given proxy.
+    fn connect(url: &str, mut opts: Options, proxy: Proxy) -> Socket {
+        opts.proxy = ProxyConfig::Proxy(proxy);
+        Socket::connect(Url::parse(url).unwrap(), opts, |_| {}).await().expect("Failed to connect through the proxy.")
+    }
+
+    /// Checks that the server received the request meant for it, without
+    /// the token of the relay.
+    fn check_head(head: &str, port: u16) {
+        let head = head.to_lowercase();
+        assert!(head.contains(&format!("host: localhost:{}\r\n", port)), "Unexpected request head {}.", head);
+        assert!(!head.contains(&RELAY_TOKEN_HEADER.to_lowercase()));
+    }
+
+    #[test]
+    fn ws_proxy() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (head_tx, head_rx) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            open_session(&mut stream, head_tx);
+        });
+
+        let socket = connect(&format!("http://localhost:{}/engine.io/", addr.port()), Options::default(), serve_proxy(addr));
+        assert_eq!(socket.cfg().sid(), "test");
+        check_head(&head_rx.recv_timeout(Duration::from_secs(5)).unwrap(), addr.port());
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn wss_proxy() {
+        use ::TlsConfig;
+        use openssl::crypto::hash::Type;
+        use openssl::ssl::{SslContext, SslMethod, SslStream};
+        use openssl::x509::X509Generator;
+        use std::env;
+        use std::fs::{self, File};
+        use uuid::Uuid;
+
+        // The certificate is only valid for the server's host, so the
+        // relay must not verify it against its own address.
+        let (cert, pkey) = X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(1)
+            .add_name("CN".to_owned(), "localhost".to_owned())
+            .set_sign_hash(Type::SHA256)
+            .generate()
+            .unwrap();
+        let pem_file = env::temp_dir().join(format!("engineio-{}.pem", Uuid::new_v4().simple()));
+        cert.write_pem(&mut File::create(&pem_file).unwrap()).unwrap();
+        let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+        ctx.set_certificate(&cert).unwrap();
+        ctx.set_private_key(&pkey).unwrap();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let (head_tx, head_rx) = channel();
+        thread::spawn(move || {
+            let (stream, _) = server.accept().unwrap();
+            let mut stream = SslStream::accept(&ctx, stream).unwrap();
+            open_session(&mut stream, head_tx);
+        });
+
+        let mut opts = Options::default();
+        opts.tls = TlsConfig::new().add_root_certificate(&pem_file);
+        let socket = connect(&format!("https://localhost:{}/engine.io/", addr.port()), opts, serve_proxy(addr));
+        let _ = fs::remove_file(&pem_file);
+        assert_eq!(socket.cfg().sid(), "test");
+        check_head(&head_rx.recv_timeout(Duration::from_secs(5)).unwrap(), addr.port());
+    }
+}